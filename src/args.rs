@@ -1,19 +1,77 @@
-use crate::error::Error;
-use clap::Parser;
+use crate::output::{Format, Progress};
+use clap::{CommandFactory, Parser};
+use glrepo::manifest_repo::ManifestRepo;
+use glrepo::process::OutputMode;
+use glrepo::Error;
+use glrepo::{OnDirty, Sort};
 use std::path::{Path, PathBuf};
 #[derive(Parser, Debug)]
 pub enum Command {
-    /// Fetch
-    Init,
+    /// Clone a manifest repository into the config directory and make its manifest the default.
+    Init {
+        /// URL of the manifest repository.
+        #[clap(short = 'u', long)]
+        manifest_url: String,
+        /// Branch of the manifest repository.
+        #[clap(short = 'b', long, default_value = "main")]
+        manifest_branch: String,
+        /// Manifest file in the repository.
+        #[clap(short = 'm', long, default_value = "default.yaml")]
+        manifest_name: PathBuf,
+    },
     /// Sync one or all upstream
-    Sync { projects: Vec<String> },
+    Sync {
+        projects: Vec<String>,
+        /// What to do with projects that has local changes.
+        #[clap(long, value_enum, default_value = "skip")]
+        on_dirty: OnDirty,
+        /// Checkout the commits in the manifest lock file as detached HEAD.
+        #[clap(long)]
+        locked: bool,
+        /// Shallow clone/fetch with history truncated to depth commits, overrides the manifest.
+        #[clap(long)]
+        depth: Option<u32>,
+        /// Retry fetches failing with a network error, overrides the manifest fetch_retries.
+        #[clap(long)]
+        retries: Option<u32>,
+    },
+    /// Fetch the complete history of one or all shallow projects
+    Unshallow { projects: Vec<String> },
+    /// Write the resolved commit of every project to a lock file next to the manifest.
+    Lock,
+    /// Write the selected projects with resolved paths and references in --format
+    /// yaml (default), json, toml, repo-xml or git-submodules.
+    Export {
+        /// Write to this file instead of stdout.
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Convert a google repo XML manifest to a YAML manifest written to stdout.
+    /// XML manifests can also be used directly with --manifest.
+    ImportRepoManifest {
+        /// The XML manifest, example: default.xml
+        xml: PathBuf,
+        /// Write the YAML manifest to this file instead of stdout.
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
     /// run command in shell on each project
     ForEach {
         /// Arguments passed to the shell process.
         args: String,
-        /// Max time, in milliseconds, before child process gets killed.
-        #[clap(short, long, default_value = "500")]
-        timeout_ms: u64,
+        /// Max time before the command gets terminated, example: 90s or 10m.
+        /// Overrides the project timeout in the manifest, no limit if neither is given.
+        #[clap(short, long)]
+        timeout: Option<humantime::Duration>,
+        /// Time between SIGTERM and SIGKILL when a command is terminated.
+        #[clap(long, default_value = "5s")]
+        kill_grace: humantime::Duration,
+        /// How the output of the command is shown when running parallel jobs.
+        #[clap(long, value_enum, default_value = "inherit")]
+        output: OutputMode,
+        /// Directory to write <project>.log files to, required with --output log.
+        #[clap(long, required_if_eq("output", "log"))]
+        log_dir: Option<PathBuf>,
     },
     /// List projects configuration
     List {
@@ -22,6 +80,8 @@ pub enum Command {
         #[clap(short, long)]
         path: bool,
     },
+    /// Show branch, commits ahead/behind the reference, stashes and local changes of every project
+    Status,
     /// List a projects path
     Path { project: String },
     /// List all projects that has changes (Note! untracked files is also seen as changes).
@@ -35,9 +95,12 @@ pub enum Command {
         /// Run command after creation. (use " around command. Example: -c "ls -l --color")
         #[clap(short = 'c', long)]
         run_command: String,
-        /// Max time, in milliseconds, before child process gets killed.
-        #[clap(short, long, default_value = "500")]
-        timeout_ms: u64,
+        /// Max time before the command gets terminated, example: 90s or 10m. No limit by default.
+        #[clap(short, long)]
+        timeout: Option<humantime::Duration>,
+        /// Time between SIGTERM and SIGKILL when a command is terminated.
+        #[clap(long, default_value = "5s")]
+        kill_grace: humantime::Duration,
         /// Project name
         project_name: String,
         /// Path to store local repository
@@ -47,12 +110,32 @@ pub enum Command {
         /// Branch
         #[clap(default_value = "main")]
         reference: String,
-        /// Auto sync
+        /// Sync the project by default, without it the project is added to the group notdefault
         #[clap(short, long)]
         auto_sync: bool,
     },
 }
 
+impl Command {
+    /// Name of the command as given on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Init { .. } => "init",
+            Command::Sync { .. } => "sync",
+            Command::Unshallow { .. } => "unshallow",
+            Command::Lock => "lock",
+            Command::ImportRepoManifest { .. } => "import-repo-manifest",
+            Command::Export { .. } => "export",
+            Command::ForEach { .. } => "for-each",
+            Command::List { .. } => "list",
+            Command::Status => "status",
+            Command::Path { .. } => "path",
+            Command::Changed { .. } => "changed",
+            Command::Create { .. } => "create",
+        }
+    }
+}
+
 #[derive(Parser)]
 #[clap(version, about, author)]
 pub struct Args {
@@ -60,11 +143,30 @@ pub struct Args {
     #[clap(short = 'c', long = "config-directory", default_value = "")]
     pub gl_config_home: PathBuf,
     /// If non absolute path, read gl_config_home will be prepend to this path.
-    #[clap(short = 'm', long = "manifest", default_value = "default.yaml")]
+    /// If not specified the manifest selected by init or else default.yaml.
+    #[clap(short = 'm', long = "manifest", default_value = "")]
     pub gl_manifest: PathBuf,
     /// Verbose flag 0 info, 1 debug, >= 2 trace.
     #[clap(long, short, parse(from_occurrences))]
     pub verbose: usize,
+    /// Select projects in group, prefix with '!' to exclude a group. Example: -g apps,!firmware
+    /// If not specified the manifest default_groups are selected.
+    #[clap(short = 'g', long = "group", global = true, value_delimiter = ',')]
+    pub groups: Vec<String>,
+    /// Output format of the command results. Progress and log messages are written to stderr.
+    /// toml, repo-xml and git-submodules are only supported by export.
+    #[clap(long, value_enum, global = true, default_value = "text")]
+    pub format: Format,
+    /// How progress of sync and unshallow is shown on stderr.
+    /// live falls back to a line per project and phase when stderr is not a terminal.
+    #[clap(long, value_enum, global = true, default_value = "live")]
+    pub progress: Progress,
+    /// Order projects are listed and run in. Projects are still run after the projects they depends_on.
+    #[clap(long, value_enum, global = true, default_value = "manifest")]
+    pub sort: Sort,
+    /// Do not start any more projects after the first project has failed.
+    #[clap(long, global = true)]
+    pub fail_fast: bool,
     /// Number of parallel jobs
     #[clap(long, short, default_value = "1")]
     pub jobs: usize,
@@ -73,7 +175,9 @@ pub struct Args {
 }
 
 impl Args {
-    pub fn init() -> Result<Self, Error> {
+    /// Parse the command line and set up logging.
+    /// Call expand_manifest before the manifest path is used.
+    pub fn init() -> Self {
         let mut args = Args::parse();
         let level = match args.verbose {
             0 => log::LevelFilter::Warn,
@@ -82,6 +186,16 @@ impl Args {
             _ => log::LevelFilter::Trace,
         };
 
+        // A global argument can not have other values for one subcommand.
+        if args.format.is_export_only() && !matches!(args.command, Command::Export { .. }) {
+            Args::command()
+                .error(
+                    clap::ErrorKind::InvalidValue,
+                    "--format toml, repo-xml and git-submodules are only supported by export",
+                )
+                .exit();
+        }
+
         // At least one job
         args.jobs = std::cmp::max(args.jobs, 1);
         let _ = simple_logger::SimpleLogger::new()
@@ -89,7 +203,7 @@ impl Args {
             .with_level(level)
             .init();
 
-        if args.gl_config_home.starts_with("") {
+        if args.gl_config_home.as_os_str().is_empty() {
             if let Ok(home) = std::env::var("GLREPO_CONFIG_HOME") {
                 args.gl_config_home = PathBuf::from(home);
             } else {
@@ -100,16 +214,30 @@ impl Args {
             }
         }
 
-        if !args.gl_manifest.starts_with("/") && !args.gl_manifest.starts_with("./") {
-            args.gl_manifest = Path::new(&args.gl_config_home).join(&args.gl_manifest);
+        args
+    }
+
+    /// Make the manifest path absolute.
+    /// A non absolute path is relative to gl_config_home.
+    /// Error
+    /// Error::Manifest if the manifest does not exist.
+    pub fn expand_manifest(&mut self) -> Result<(), Error> {
+        if self.gl_manifest.as_os_str().is_empty() {
+            self.gl_manifest = match ManifestRepo::load(&self.gl_config_home)? {
+                Some(repo) => repo.manifest_file(&self.gl_config_home),
+                None => PathBuf::from("default.yaml"),
+            };
+        }
+        if !self.gl_manifest.starts_with("/") && !self.gl_manifest.starts_with("./") {
+            self.gl_manifest = Path::new(&self.gl_config_home).join(&self.gl_manifest);
         }
-        args.gl_manifest = args.gl_manifest.canonicalize().map_err(|e| {
-            Error::General(format!(
+        self.gl_manifest = self.gl_manifest.canonicalize().map_err(|e| {
+            Error::Manifest(format!(
                 "Expand: '{}' failed cause: {}",
-                args.gl_manifest.display(),
+                self.gl_manifest.display(),
                 e
             ))
         })?;
-        Ok(args)
+        Ok(())
     }
 }
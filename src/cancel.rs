@@ -0,0 +1,43 @@
+use crate::error::{Error, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+///
+/// Stops running projects and drops queued projects when cancelled.
+/// Clones share the same state, every Workspace has its own token.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop running projects and drop queued projects.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true if cancel has been called.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+///
+/// Install the Ctrl-C handler.
+/// The first Ctrl-C cancels 'token', which asks running jobs to stop and drops queued jobs,
+/// a second Ctrl-C exits immediately.
+/// Error
+/// Error::General if the handler could not be installed.
+pub fn install(token: CancelToken) -> Result<()> {
+    ctrlc::set_handler(move || {
+        if token.is_cancelled() {
+            eprintln!("\nInterrupted again, exit");
+            std::process::exit(130);
+        }
+        token.cancel();
+        eprintln!("\nInterrupted, stopping running projects (Ctrl-C again to exit now)");
+    })
+    .map_err(|e| Error::General(format!("Could not install Ctrl-C handler cause: {}", e)))
+}
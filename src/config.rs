@@ -0,0 +1,157 @@
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A SSH private key, optional with the name of an environment variable holding its passphrase.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum SshKey {
+    Path(PathBuf),
+    WithPassphrase {
+        path: PathBuf,
+        passphrase_env: String,
+    },
+}
+
+impl SshKey {
+    /// Returns the key path with a leading ~/ expanded to $HOME.
+    pub fn path(&self) -> PathBuf {
+        let path = match self {
+            SshKey::Path(path) => path,
+            SshKey::WithPassphrase { path, .. } => path,
+        };
+        match path.strip_prefix("~") {
+            Ok(rest) => PathBuf::from(std::env::var("HOME").unwrap_or_default()).join(rest),
+            Err(_) => path.clone(),
+        }
+    }
+
+    pub fn passphrase(&self) -> Option<String> {
+        match self {
+            SshKey::Path(_) => None,
+            SshKey::WithPassphrase { passphrase_env, .. } => std::env::var(passphrase_env).ok(),
+        }
+    }
+}
+
+/// Credentials tried, in order, when a remote requires authentication.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Credentials {
+    /// Try keys from ssh-agent before the ssh_keys.
+    #[serde(default = "default_true")]
+    pub ssh_agent: bool,
+    /// SSH private keys, keys that does not exist are skipped.
+    #[serde(default = "default_ssh_keys")]
+    pub ssh_keys: Vec<SshKey>,
+    /// Try git credential.helper for HTTPS.
+    #[serde(default = "default_true")]
+    pub credential_helper: bool,
+    /// Environment variables with tokens for HTTPS.
+    #[serde(default = "default_token_env")]
+    pub token_env: Vec<String>,
+    /// User name sent together with a token if the URL does not have one.
+    #[serde(default = "default_token_user")]
+    pub token_user: String,
+    /// Max number of credentials tried for one remote before giving up.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: usize,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_ssh_keys() -> Vec<SshKey> {
+    ["~/.ssh/id_ed25519", "~/.ssh/id_ecdsa", "~/.ssh/id_rsa"]
+        .iter()
+        .map(|p| SshKey::Path(PathBuf::from(p)))
+        .collect()
+}
+
+fn default_token_env() -> Vec<String> {
+    vec![
+        String::from("GLREPO_TOKEN"),
+        String::from("GITLAB_TOKEN"),
+        String::from("GITHUB_TOKEN"),
+    ]
+}
+
+fn default_token_user() -> String {
+    String::from("oauth2")
+}
+
+fn default_max_attempts() -> usize {
+    8
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Self {
+            ssh_agent: true,
+            ssh_keys: default_ssh_keys(),
+            credential_helper: true,
+            token_env: default_token_env(),
+            token_user: default_token_user(),
+            max_attempts: default_max_attempts(),
+        }
+    }
+}
+
+/// glrepo configuration read from config.yaml in the config directory.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct GlConfig {
+    #[serde(default)]
+    pub credentials: Credentials,
+}
+
+impl GlConfig {
+    /// Read configuration, if the file does not exist the default configuration is returned.
+    /// Error
+    /// Error::General
+    pub fn try_from_yaml<P: AsRef<Path>>(config_file: &P) -> Result<Self, Error> {
+        let config_file = config_file.as_ref();
+        if !config_file.exists() {
+            return Ok(Self::default());
+        }
+        let s = fs::read_to_string(config_file).map_err(|e| {
+            Error::General(format!(
+                "Could not load config: '{}' cause: {}",
+                config_file.display(),
+                e
+            ))
+        })?;
+        serde_yaml::from_str::<GlConfig>(&s)
+            .map_err(|e| Error::General(format!("'{}': {}", config_file.display(), e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_credentials_config() {
+        let yaml: &str = r"---
+            credentials:
+                ssh_agent: false
+                ssh_keys:
+                    - /keys/id_rsa
+                    - path: /keys/id_work
+                      passphrase_env: WORK_KEY_PASSPHRASE";
+
+        let config = serde_yaml::from_str::<GlConfig>(yaml).unwrap();
+        assert!(!config.credentials.ssh_agent);
+        assert_eq!(
+            vec![
+                SshKey::Path(PathBuf::from("/keys/id_rsa")),
+                SshKey::WithPassphrase {
+                    path: PathBuf::from("/keys/id_work"),
+                    passphrase_env: String::from("WORK_KEY_PASSPHRASE"),
+                }
+            ],
+            config.credentials.ssh_keys
+        );
+        assert_eq!(default_token_env(), config.credentials.token_env);
+        assert_eq!(8, config.credentials.max_attempts);
+    }
+}
@@ -0,0 +1,302 @@
+use crate::config::Credentials;
+use git2::{Cred, CredentialType};
+
+///
+/// Credentials to try for one remote operation.
+/// libgit2 calls the credentials callback again every time authentication fails,
+/// so every step in the chain is only tried once and the number of attempts is limited.
+///
+/// Order:
+/// 1. ssh-agent
+/// 2. SSH keys from the configuration
+/// 3. git credential.helper
+/// 4. Tokens from environment variables
+pub struct CredentialChain {
+    config: Credentials,
+    attempts: usize,
+    username_sent: bool,
+    ssh_agent_tried: bool,
+    ssh_key_index: usize,
+    helper_tried: bool,
+    token_index: usize,
+}
+
+impl CredentialChain {
+    pub fn new(config: Credentials) -> Self {
+        Self {
+            config,
+            attempts: 0,
+            username_sent: false,
+            ssh_agent_tried: false,
+            ssh_key_index: 0,
+            helper_tried: false,
+            token_index: 0,
+        }
+    }
+
+    fn next_ssh(&mut self, username: &str) -> Option<Cred> {
+        if !self.ssh_agent_tried {
+            self.ssh_agent_tried = true;
+            if self.config.ssh_agent && std::env::var_os("SSH_AUTH_SOCK").is_some() {
+                log::debug!("Credentials: Try ssh-agent");
+                if let Ok(cred) = Cred::ssh_key_from_agent(username) {
+                    return Some(cred);
+                }
+            }
+        }
+        while let Some(key) = self.config.ssh_keys.get(self.ssh_key_index) {
+            self.ssh_key_index += 1;
+            let path = key.path();
+            if !path.exists() {
+                continue;
+            }
+            log::debug!("Credentials: Try SSH key: '{}'", path.display());
+            if let Ok(cred) = Cred::ssh_key(username, None, &path, key.passphrase().as_deref()) {
+                return Some(cred);
+            }
+        }
+        None
+    }
+
+    fn next_userpass(&mut self, url: &str, username: Option<&str>) -> Option<Cred> {
+        if !self.helper_tried {
+            self.helper_tried = true;
+            if self.config.credential_helper {
+                log::debug!("Credentials: Try git credential.helper");
+                if let Ok(cred) = git2::Config::open_default()
+                    .and_then(|config| Cred::credential_helper(&config, url, username))
+                {
+                    return Some(cred);
+                }
+            }
+        }
+        while let Some(env) = self.config.token_env.get(self.token_index) {
+            self.token_index += 1;
+            if let Ok(token) = std::env::var(env) {
+                log::debug!("Credentials: Try token from: {}", env);
+                let user = username.unwrap_or(&self.config.token_user);
+                if let Ok(cred) = Cred::userpass_plaintext(user, &token) {
+                    return Some(cred);
+                }
+            }
+        }
+        None
+    }
+
+    ///
+    /// Returns the next credential to try for 'url'.
+    /// Fails when all credentials has been tried or max_attempts is reached.
+    pub fn next(
+        &mut self,
+        url: &str,
+        username_from_url: Option<&str>,
+        allowed: CredentialType,
+    ) -> Result<Cred, git2::Error> {
+        self.attempts += 1;
+        if self.attempts > self.config.max_attempts {
+            return Err(git2::Error::from_str(&format!(
+                "Authentication failed for: '{}' after {} attempts",
+                url, self.config.max_attempts
+            )));
+        }
+
+        if allowed.contains(CredentialType::USERNAME) && !self.username_sent {
+            self.username_sent = true;
+            return Cred::username(username_from_url.unwrap_or("git"));
+        }
+
+        if allowed.contains(CredentialType::SSH_KEY) {
+            if let Some(cred) = self.next_ssh(username_from_url.unwrap_or("git")) {
+                return Ok(cred);
+            }
+        }
+
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            if let Some(cred) = self.next_userpass(url, username_from_url) {
+                return Ok(cred);
+            }
+        }
+
+        Err(git2::Error::from_str(&format!(
+            "Authentication failed for: '{}', no more credentials to try",
+            url
+        )))
+    }
+}
+
+/// Quote 's' for sh.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Returns true if 'name' can be used as a shell variable.
+fn is_env_name(name: &str) -> bool {
+    name.chars().all(|c| c == '_' || c.is_ascii_alphanumeric())
+        && name.chars().next().is_some_and(|c| !c.is_ascii_digit())
+}
+
+///
+/// Options and environment for the git command, so it uses the same credentials
+/// as the chain and never prompts for any.
+/// SSH keys are given to ssh with -i, unless GIT_SSH_COMMAND or core.sshCommand is set.
+/// The first token found in the environment is returned by a credential helper
+/// after the configured helpers, the token itself is never on the command line.
+/// Returns the options to put before the git command and the environment.
+pub fn git_cli_config(config: &Credentials) -> (Vec<String>, Vec<(String, String)>) {
+    let mut args = vec![];
+    let mut env = vec![(String::from("GIT_TERMINAL_PROMPT"), String::from("0"))];
+    let ssh_command = std::env::var_os("GIT_SSH_COMMAND").is_some()
+        || git2::Config::open_default()
+            .and_then(|c| c.get_string("core.sshCommand"))
+            .is_ok();
+    if !ssh_command {
+        let mut ssh = String::from("ssh -o BatchMode=yes");
+        if !config.ssh_agent {
+            ssh.push_str(" -o IdentityAgent=none");
+        }
+        for path in config.ssh_keys.iter().map(|key| key.path()) {
+            if path.exists() {
+                ssh.push_str(" -i ");
+                ssh.push_str(&shell_quote(&path.to_string_lossy()));
+            }
+        }
+        env.push((String::from("GIT_SSH_COMMAND"), ssh));
+    }
+    if !config.credential_helper {
+        // An empty helper clears the helpers from the git configuration.
+        args.extend([String::from("-c"), String::from("credential.helper=")]);
+    }
+    let token_env = config
+        .token_env
+        .iter()
+        .find(|name| is_env_name(name) && std::env::var_os(name).is_some());
+    if let Some(token_env) = token_env {
+        let username = shell_quote(&format!("username={}", config.token_user));
+        args.extend([
+            String::from("-c"),
+            format!(
+                "credential.helper=!f() {{ test \"$1\" = get || return 0; echo {}; echo \"password=${}\"; }}; f",
+                username, token_env
+            ),
+        ]);
+    }
+    (args, env)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SshKey;
+    use std::path::PathBuf;
+
+    /// Returns the type of the credential 'next' returned, or the error message.
+    fn next(chain: &mut CredentialChain, allowed: CredentialType) -> Result<u32, String> {
+        chain
+            .next("ssh://git@example.com/repo", Some("git"), allowed)
+            .map(|cred| cred.credtype())
+            .map_err(|e| e.message().to_string())
+    }
+
+    #[test]
+    fn test_credential_chain() {
+        let dir = std::env::temp_dir().join(format!("glrepo-chain-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let keys: Vec<PathBuf> = ["a", "b"].iter().map(|k| dir.join(k)).collect();
+        for key in &keys {
+            std::fs::write(key, "key").unwrap();
+        }
+        let token_env: Vec<String> = ["UNSET", "A", "B"]
+            .iter()
+            .map(|t| format!("GLREPO_TEST_CHAIN_{}_{}", t, std::process::id()))
+            .collect();
+        std::env::set_var(&token_env[1], "a");
+        std::env::set_var(&token_env[2], "b");
+        let config = Credentials {
+            ssh_agent: false,
+            ssh_keys: vec![
+                SshKey::Path(keys[0].clone()),
+                SshKey::Path(dir.join("missing")),
+                SshKey::Path(keys[1].clone()),
+            ],
+            credential_helper: false,
+            token_env: token_env.clone(),
+            max_attempts: 10,
+            ..Default::default()
+        };
+        let ssh = CredentialType::SSH_KEY.bits();
+        let userpass = CredentialType::USER_PASS_PLAINTEXT.bits();
+
+        // The username first, then every existing key once, then the end of the chain.
+        let mut chain = CredentialChain::new(config.clone());
+        let allowed = CredentialType::USERNAME | CredentialType::SSH_KEY;
+        assert_eq!(
+            Ok(CredentialType::USERNAME.bits()),
+            next(&mut chain, allowed)
+        );
+        assert_eq!(Ok(ssh), next(&mut chain, allowed));
+        assert_eq!(Ok(ssh), next(&mut chain, allowed));
+        let end = next(&mut chain, allowed).unwrap_err();
+        assert!(end.contains("no more credentials"), "{}", end);
+
+        // SSH keys before tokens, every token that is set once.
+        let mut chain = CredentialChain::new(config.clone());
+        let allowed = CredentialType::SSH_KEY | CredentialType::USER_PASS_PLAINTEXT;
+        let types: Vec<_> = (0..4).map(|_| next(&mut chain, allowed).unwrap()).collect();
+        assert_eq!(vec![ssh, ssh, userpass, userpass], types);
+        assert!(next(&mut chain, allowed).is_err());
+
+        // Tokens are not tried when only SSH keys are allowed, and the other way around.
+        let mut chain = CredentialChain::new(config.clone());
+        assert_eq!(
+            Ok(userpass),
+            next(&mut chain, CredentialType::USER_PASS_PLAINTEXT)
+        );
+        assert_eq!(Ok(ssh), next(&mut chain, CredentialType::SSH_KEY));
+
+        // Attempts are limited even if there are credentials left.
+        let mut chain = CredentialChain::new(Credentials {
+            max_attempts: 2,
+            ..config
+        });
+        assert!(next(&mut chain, allowed).is_ok());
+        assert!(next(&mut chain, allowed).is_ok());
+        let limit = next(&mut chain, allowed).unwrap_err();
+        assert!(limit.contains("after 2 attempts"), "{}", limit);
+
+        for env in &token_env {
+            std::env::remove_var(env);
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_git_cli_config() {
+        let key = std::env::temp_dir().join(format!("glrepo-cli-key-{}", std::process::id()));
+        std::fs::write(&key, "key").unwrap();
+        let token_env = format!("GLREPO_TEST_CLI_TOKEN_{}", std::process::id());
+        std::env::set_var(&token_env, "secret");
+        let config = Credentials {
+            ssh_agent: false,
+            ssh_keys: vec![
+                SshKey::Path(key.clone()),
+                SshKey::Path(PathBuf::from("/missing")),
+            ],
+            credential_helper: false,
+            token_env: vec![String::from("GLREPO_TEST_CLI_UNSET"), token_env.clone()],
+            ..Default::default()
+        };
+        let (args, env) = git_cli_config(&config);
+        assert_eq!(vec!["-c", "credential.helper="], args[..2]);
+        assert!(args[3].starts_with("credential.helper=!"));
+        assert!(args[3].contains(&format!("password=${}", token_env)));
+        assert!(!args.iter().any(|arg| arg.contains("secret")));
+        assert!(env.contains(&(String::from("GIT_TERMINAL_PROMPT"), String::from("0"))));
+        if let Some((_, ssh)) = env.iter().find(|(k, _)| k == "GIT_SSH_COMMAND") {
+            assert!(ssh.contains("IdentityAgent=none"));
+            assert!(ssh.contains(&format!("-i '{}'", key.display())));
+            assert!(!ssh.contains("/missing"));
+        }
+        std::env::remove_var(&token_env);
+        std::fs::remove_file(&key).ok();
+    }
+}
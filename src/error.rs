@@ -1,5 +1,52 @@
+use crate::manifest::SyncStrategy;
 use std::fmt;
 pub type Result<T> = std::result::Result<T, Error>;
+
+/// Why a command failed for one or more projects, decides the exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// Some projects failed.
+    Partial,
+    /// No project succeeded.
+    Total,
+    /// At least one command was terminated due to timeout.
+    Timeout,
+    /// At least one project failed since its part of the manifest is invalid.
+    Manifest,
+    /// Stopped by Ctrl-C.
+    Interrupted,
+}
+
+impl Failure {
+    /// Classify the 'errors' of a command where 'succeeded' projects did not fail.
+    /// Interrupted has priority over an invalid manifest, which has priority over timeout
+    /// which has priority over total failure.
+    pub fn classify<'a, I: IntoIterator<Item = &'a Error>>(
+        errors: I,
+        succeeded: usize,
+        interrupted: bool,
+    ) -> Self {
+        if interrupted {
+            return Failure::Interrupted;
+        }
+        let errors: Vec<&Error> = errors.into_iter().collect();
+        if errors.is_empty() {
+            return Failure::Partial;
+        }
+        if errors.iter().any(|e| matches!(e, Error::Manifest(_))) {
+            Failure::Manifest
+        } else if errors
+            .iter()
+            .any(|e| matches!(e, Error::ShellCommandTimeout(_, _)))
+        {
+            Failure::Timeout
+        } else if succeeded == 0 {
+            Failure::Total
+        } else {
+            Failure::Partial
+        }
+    }
+}
 #[derive(Debug)]
 pub enum Error {
     /// Git errors
@@ -8,10 +55,20 @@ pub enum Error {
     Git(&'static str, git2::Error),
     /// General error
     General(String),
-    /// Summary error
-    Summary(String),
-    /// Not supported
-    NotSupported(&'static str),
+    /// Summary of failed projects
+    Summary(Failure, String),
+    /// Local branch has diverged from upstream 'project' 'strategy'
+    SyncDiverged(String, SyncStrategy),
+    /// Sync stopped due to conflicts 'project' 'files'
+    SyncConflict(String, Vec<String>),
+    /// HEAD is on another local branch than the reference 'project' 'branch' 'reference'
+    OtherBranch(String, String, String),
+    /// Working tree has local changes 'project'
+    DirtyWorkingTree(String),
+    /// Stashed changes could not be re-applied 'project' 'git2::error'
+    StashPop(String, git2::Error),
+    /// Reference not found 'project' 'reference'
+    ReferenceNotFound(String, String),
     /// Project 'name' not found
     ProjectNotFound(String),
     /// Manifest error
@@ -22,6 +79,12 @@ pub enum Error {
     ShellCommandTimeout(String, String),
     /// Command timeout 'project_name' 'command' 'exit code'
     ShellCommandExit(String, String, i32),
+    /// Command failed 'project_name' 'command' 'exit code' 'stderr'
+    CommandFailed(String, String, i32, String),
+    /// Stopped by Ctrl-C 'project'
+    Interrupted(String),
+    /// Network operation failed after retries 'project' 'attempts' 'error of the last attempt'
+    Retried(String, u32, Box<Error>),
 }
 
 impl fmt::Display for Error {
@@ -31,8 +94,47 @@ impl fmt::Display for Error {
                 write!(f, "Git {}: {}", command, e.message())
             }
             Error::General(s) => write!(f, "{}", s),
-            Error::NotSupported(s) => write!(f, "{}", s),
-            Error::Summary(s) => write!(f, "{}", s),
+            Error::SyncDiverged(p, strategy) => {
+                write!(
+                    f,
+                    "{}: Local branch has diverged from upstream and sync_strategy is: {}",
+                    p, strategy
+                )
+            }
+            Error::SyncConflict(p, files) => {
+                write!(
+                    f,
+                    "{}: Sync aborted due to conflicts in: {}",
+                    p,
+                    files.join(", ")
+                )
+            }
+            Error::OtherBranch(p, branch, reference) => {
+                write!(
+                    f,
+                    "{}: HEAD is on branch: '{}' not on: '{}', checkout '{}' to sync it",
+                    p, branch, reference, reference
+                )
+            }
+            Error::DirtyWorkingTree(p) => {
+                write!(f, "{}: Working tree has local changes, sync aborted", p)
+            }
+            Error::StashPop(p, e) => {
+                write!(
+                    f,
+                    "{}: Local changes could not be re-applied cause: '{}', they are kept in the stash, see 'git stash list'",
+                    p,
+                    e.message()
+                )
+            }
+            Error::ReferenceNotFound(p, r) => {
+                write!(
+                    f,
+                    "{}: Reference: '{}' is not a branch, tag or commit id",
+                    p, r
+                )
+            }
+            Error::Summary(_, s) => write!(f, "{}", s),
             Error::ProjectNotFound(name) => write!(f, "Project: '{}' not found.", name),
             Error::Manifest(s) => write!(f, "Manifest: {}", s),
             Error::ShellCommand(p, s, e) => {
@@ -48,6 +150,72 @@ impl fmt::Display for Error {
                     p, s, code
                 )
             }
+            Error::Interrupted(p) => write!(f, "{}: Interrupted", p),
+            Error::CommandFailed(p, s, code, stderr) => {
+                write!(f, "{}: Command: '{}' failed with exit code: {}", p, s, code)?;
+                let lines: Vec<&str> = stderr.lines().filter(|l| !l.trim().is_empty()).collect();
+                if !lines.is_empty() {
+                    write!(f, ": {}", lines.join("; "))?;
+                }
+                Ok(())
+            }
+            Error::Retried(p, attempts, e) => {
+                write!(f, "{}: {} (after {} attempts)", p, e, attempts)
+            }
         }
     }
 }
+
+impl Error {
+    ///
+    /// Process exit code:
+    /// - 1 Error not covered below.
+    /// - 3 Some projects failed.
+    /// - 4 All projects failed.
+    /// - 5 A command was terminated due to timeout.
+    /// - 6 The manifest, its includes or lock file is invalid.
+    /// - 130 Interrupted by Ctrl-C.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Summary(Failure::Partial, _) => 3,
+            Error::Summary(Failure::Total, _) => 4,
+            Error::Summary(Failure::Timeout, _) | Error::ShellCommandTimeout(_, _) => 5,
+            Error::Summary(Failure::Manifest, _) | Error::Manifest(_) => 6,
+            Error::Summary(Failure::Interrupted, _) | Error::Interrupted(_) => 130,
+            _ => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_failure_classify() {
+        let failed = Error::General(String::from("failed"));
+        let timeout = Error::ShellCommandTimeout(String::from("a"), String::from("make"));
+        assert_eq!(Failure::Partial, Failure::classify([&failed], 1, false));
+        assert_eq!(Failure::Total, Failure::classify([&failed], 0, false));
+        assert_eq!(
+            Failure::Timeout,
+            Failure::classify([&failed, &timeout], 0, false)
+        );
+        assert_eq!(Failure::Interrupted, Failure::classify([&timeout], 1, true));
+        let manifest = Error::Manifest(String::from("invalid"));
+        assert_eq!(
+            Failure::Manifest,
+            Failure::classify([&failed, &timeout, &manifest], 1, false)
+        );
+        assert_eq!(Failure::Manifest, Failure::classify([&manifest], 0, false));
+        assert_eq!(
+            Failure::Interrupted,
+            Failure::classify([&manifest], 0, true)
+        );
+        assert_eq!(
+            6,
+            Error::Summary(Failure::Manifest, String::new()).exit_code()
+        );
+        assert_eq!(4, Error::Summary(Failure::Total, String::new()).exit_code());
+        assert_eq!(6, Error::Manifest(String::new()).exit_code());
+    }
+}
@@ -0,0 +1,230 @@
+use crate::error::{Error, Result};
+use crate::git::{is_commit_reference, SyncContext};
+use crate::manifest::{GlProject, GlProjects};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+///
+/// Returns the manifest with only the 'selected' projects.
+/// Includes are already merged and fetch URLs, paths and references resolved.
+pub fn resolved(projects: &GlProjects, selected: Vec<GlProject>) -> GlProjects {
+    GlProjects {
+        include: vec![],
+        remove: vec![],
+        projects: selected
+            .into_iter()
+            .map(|project| (project.name.clone(), project))
+            .collect(),
+        ..projects.clone()
+    }
+}
+
+///
+/// Path of 'project' relative to 'projects_dir'.
+/// Error
+/// Error::General if the project is not inside the projects_dir.
+pub fn relative_path(project: &GlProject, projects_dir: &Path) -> Result<PathBuf> {
+    project
+        .path
+        .strip_prefix(projects_dir)
+        .ok()
+        .filter(|path| !path.has_root() && path.file_name().is_some())
+        .map(Path::to_path_buf)
+        .ok_or_else(|| {
+            Error::General(format!(
+                "Project: {} path: '{}' is outside projects_dir: '{}'",
+                project.name,
+                project.path.display(),
+                projects_dir.display()
+            ))
+        })
+}
+
+/// Returns the manifest as a JSON value with keys sorted.
+fn to_value(projects: &GlProjects) -> Result<serde_json::Value> {
+    serde_json::to_value(projects).map_err(|e| Error::General(e.to_string()))
+}
+
+/// Serialize to JSON with projects sorted by name.
+pub fn to_json(projects: &GlProjects) -> Result<String> {
+    serde_json::to_string_pretty(&to_value(projects)?).map_err(|e| Error::General(e.to_string()))
+}
+
+/// Serialize to YAML with projects sorted by name.
+pub fn to_yaml(projects: &GlProjects) -> Result<String> {
+    serde_yaml::to_string(&to_value(projects)?).map_err(|e| Error::General(e.to_string()))
+}
+
+/// Serialize to TOML with projects sorted by name.
+pub fn to_toml(projects: &GlProjects) -> Result<String> {
+    toml::to_string(&to_value(projects)?).map_err(|e| Error::General(e.to_string()))
+}
+
+/// Quote 's' as a git config value, or as a subsection name if 'subsection'.
+fn config_quote(s: &str, subsection: bool) -> String {
+    let escaped = s.replace('\\', "\\\\").replace('"', "\\\"");
+    let plain = !subsection && escaped == s && s.trim() == s && !s.contains(['#', ';']);
+    if plain {
+        escaped
+    } else {
+        format!("\"{}\"", escaped)
+    }
+}
+
+///
+/// Returns a .gitmodules file with every project as a submodule,
+/// paths are relative to the projects_dir.
+/// The reference is used as branch unless it is a commit id, which .gitmodules can not hold.
+/// A reference named like a commit id is looked up in the checkout or the remote using 'ctx'.
+/// Error
+/// Error::General if a project is outside the projects_dir or has a line break in its name,
+/// path, URL or reference.
+/// Error::Git if the remote could not be listed.
+pub fn to_gitmodules(projects: &GlProjects, ctx: &SyncContext) -> Result<String> {
+    let sorted: BTreeMap<_, _> = projects.projects.iter().collect();
+    let mut s = String::new();
+    for (name, project) in sorted {
+        let path = relative_path(project, &projects.projects_dir)?;
+        let path = path.to_string_lossy();
+        let values = [name.as_str(), &path, &project.fetch_url, &project.reference];
+        if values.iter().any(|v| v.contains(['\n', '\r'])) {
+            return Err(Error::General(format!(
+                "Project: {} can not be written to .gitmodules, it has a line break",
+                name.escape_debug()
+            )));
+        }
+        s += &format!("[submodule {}]\n", config_quote(name, true));
+        s += &format!("\tpath = {}\n", config_quote(&path, false));
+        s += &format!("\turl = {}\n", config_quote(&project.fetch_url, false));
+        if is_commit_reference(project, ctx)? {
+            log::warn!(
+                "Project: {} reference: {} is a commit id, it is left out of .gitmodules",
+                name,
+                project.reference
+            );
+        } else {
+            s += &format!("\tbranch = {}\n", config_quote(&project.reference, false));
+        }
+        if project.depth.is_some() {
+            s += "\tshallow = true\n";
+        }
+    }
+    Ok(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    /// A bare repository with an empty commit on each of 'branches'.
+    fn remote(path: &Path, branches: &[&str]) {
+        let repo = git2::Repository::init_bare(path).unwrap();
+        let tree = repo.find_tree(repo.treebuilder(None).unwrap().write().unwrap());
+        let sig = git2::Signature::now("test", "test@localhost").unwrap();
+        for branch in branches {
+            repo.commit(
+                Some(&format!("refs/heads/{}", branch)),
+                &sig,
+                &sig,
+                "empty",
+                tree.as_ref().unwrap(),
+                &[],
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_export() {
+        let yaml: &str = r"---
+            projects_dir: /src
+            default_reference: main
+            include: [common.yaml]
+            projects:
+                batchecker:
+                    fetch_url: https://gitlab.com/mike7b4/batchecker.git
+                    path: /src/apps/batchecker
+                    reference: main
+                    depth: 1
+                linux:
+                    fetch_url: https://github.com/torvalds/linux.git
+                    path: /opt/linux
+                    reference: 0123456789abcdef0123456789abcdef01234567
+                skipped:
+                    fetch_url: https://skipped";
+        let manifest = serde_yaml::from_str::<GlProjects>(yaml).unwrap();
+        let selected = ["linux", "batchecker"]
+            .iter()
+            .map(|name| {
+                let mut project = manifest.projects[*name].clone();
+                project.name = name.to_string();
+                project
+            })
+            .collect();
+        let exported = resolved(&manifest, selected);
+        assert!(exported.include.is_empty());
+        let ctx = SyncContext::default();
+        // linux is outside the projects_dir.
+        assert!(matches!(
+            to_gitmodules(&exported, &ctx),
+            Err(Error::General(_))
+        ));
+        let dir = std::env::temp_dir().join(format!("glrepo-export-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        remote(&dir.join("linux.git"), &["main"]);
+        remote(&dir.join("hex.git"), &["deadbeef"]);
+        let mut inside = exported.clone();
+        let linux = inside.projects.get_mut("linux").unwrap();
+        linux.path = PathBuf::from("/src/linux");
+        linux.fetch_url = dir.join("linux.git").to_string_lossy().to_string();
+        // A branch named like a commit id is kept.
+        inside.projects.insert(
+            String::from("hex"),
+            GlProject {
+                path: PathBuf::from("/src/hex"),
+                fetch_url: dir.join("hex.git").to_string_lossy().to_string(),
+                reference: String::from("deadbeef"),
+                ..Default::default()
+            },
+        );
+        inside.projects.insert(
+            String::from("odd \"name\""),
+            GlProject {
+                path: PathBuf::from("/src/odd #1"),
+                fetch_url: String::from("https://odd"),
+                reference: String::from("main"),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            format!(
+                "[submodule \"batchecker\"]\n\
+                 \tpath = apps/batchecker\n\
+                 \turl = https://gitlab.com/mike7b4/batchecker.git\n\
+                 \tbranch = main\n\
+                 \tshallow = true\n\
+                 [submodule \"hex\"]\n\
+                 \tpath = hex\n\
+                 \turl = {}\n\
+                 \tbranch = deadbeef\n\
+                 [submodule \"linux\"]\n\
+                 \tpath = linux\n\
+                 \turl = {}\n\
+                 [submodule \"odd \\\"name\\\"\"]\n\
+                 \tpath = \"odd #1\"\n\
+                 \turl = https://odd\n\
+                 \tbranch = main\n",
+                dir.join("hex.git").display(),
+                dir.join("linux.git").display()
+            ),
+            to_gitmodules(&inside, &ctx).unwrap()
+        );
+        inside.projects.get_mut("linux").unwrap().fetch_url = String::from("https://x\n[core]");
+        assert!(to_gitmodules(&inside, &ctx).is_err());
+        std::fs::remove_dir_all(&dir).ok();
+        let toml = to_toml(&exported).unwrap();
+        assert!(toml.contains("[projects.batchecker]\n"));
+        assert!(toml.find("batchecker").unwrap() < toml.find("linux").unwrap());
+        let json: serde_json::Value = serde_json::from_str(&to_json(&exported).unwrap()).unwrap();
+        assert_eq!("/opt/linux", json["projects"]["linux"]["path"]);
+    }
+}
@@ -1,14 +1,39 @@
+use crate::cancel::CancelToken;
+use crate::config::Credentials;
+use crate::credentials::{self, CredentialChain};
 use crate::error::{Error, Result};
-use crate::manifest::GlProject;
-use git2::{build::CheckoutBuilder, Cred, FetchOptions, Repository, Statuses};
+use crate::manifest::{GitRemote, GlProject, SyncStrategy};
+use crate::process;
+use crate::report::{Event, Phase, Quiet, Reporter, SharedReporter};
+use git2::{build::CheckoutBuilder, FetchOptions, Repository, Statuses};
+use serde::Serialize;
 use std::collections::HashMap;
 use std::fmt;
-use std::io::Write;
 use std::path::Path;
 pub struct Git {
     repo: Repository,
 }
 
+///
+/// What network operations need besides the project,
+/// where progress is sent, how to authenticate and when to stop.
+#[derive(Clone)]
+pub struct SyncContext {
+    pub reporter: SharedReporter,
+    pub credentials: Credentials,
+    pub cancel: CancelToken,
+}
+
+impl Default for SyncContext {
+    fn default() -> Self {
+        Self {
+            reporter: std::sync::Arc::new(Quiet),
+            credentials: Credentials::default(),
+            cancel: CancelToken::new(),
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct ChangedFiles {
     files: HashMap<String, git2::Status>,
@@ -18,6 +43,80 @@ impl ChangedFiles {
     pub fn is_empty(&self) -> bool {
         self.files.is_empty()
     }
+
+    /// Iterate over the changed files and their status.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &git2::Status)> {
+        self.files.iter()
+    }
+
+    /// Returns true if any file is changed or untracked.
+    /// Untracked files make the working tree dirty since sync could overwrite them.
+    pub fn is_dirty(&self) -> bool {
+        !self.files.is_empty()
+    }
+}
+
+/// What sync should do with a project that has local changes.
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnDirty {
+    /// Leave the project as is and continue with the next project.
+    Skip,
+    /// Stash local changes and re-apply them after sync.
+    Stash,
+    /// Fail the project.
+    Abort,
+}
+
+/// Outcome of a successful sync.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncStatus {
+    /// Project is synced with upstream.
+    Synced,
+    /// Project is synced and local changes were stashed and re-applied.
+    Stashed,
+    /// Project was not synced since the working tree has local changes.
+    SkippedDirty,
+}
+
+/// Result of a successful sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncReport {
+    pub status: SyncStatus,
+    /// Attempts needed by the fetch that was retried the most, 1 if no fetch was retried.
+    pub attempts: u32,
+}
+
+impl fmt::Display for SyncStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncStatus::Synced => write!(f, "synced"),
+            SyncStatus::Stashed => write!(f, "synced, local changes stashed and re-applied"),
+            SyncStatus::SkippedDirty => write!(f, "skipped, working tree has local changes"),
+        }
+    }
+}
+
+/// State of a local repository compared to its manifest reference.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProjectStatus {
+    /// Checked out branch, None if HEAD is detached.
+    pub branch: Option<String>,
+    /// Manifest reference.
+    pub reference: String,
+    /// Commit id of HEAD, None if nothing is committed.
+    pub head: Option<String>,
+    pub detached: bool,
+    /// Commits in HEAD not in origin/<reference>, None if the reference is not found.
+    pub ahead: Option<usize>,
+    /// Commits in origin/<reference> not in HEAD, None if the reference is not found.
+    pub behind: Option<usize>,
+    pub stashes: usize,
+    /// Number of changed tracked files.
+    pub dirty: usize,
+    /// Number of untracked files.
+    pub untracked: usize,
 }
 
 impl fmt::Display for ChangedFiles {
@@ -34,20 +133,298 @@ impl fmt::Display for ChangedFiles {
     }
 }
 
+/// What a manifest reference was resolved to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RefKind {
+    /// Remote branch origin/<reference>
+    Branch,
+    Tag,
+    Commit,
+}
+
+/// Returns true if 'reference' is a commit id, full or abbreviated to at least 7 characters.
+pub(crate) fn is_commit_id(reference: &str) -> bool {
+    (7..=40).contains(&reference.len()) && reference.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+///
+/// Returns true if the reference of 'project' is a commit id and not the name of a branch
+/// or tag, which resolve_reference would use first.
+/// The refs of the checkout are used if it exists, else the refs of the remote.
+/// Error
+/// Error::Git if the remote could not be listed.
+pub(crate) fn is_commit_reference(project: &GlProject, ctx: &SyncContext) -> Result<bool> {
+    let reference = &project.reference;
+    if !is_commit_id(reference) {
+        return Ok(false);
+    }
+    if let Ok(repo) = Repository::open(&project.path) {
+        let is_ref = repo
+            .find_reference(&format!("refs/remotes/origin/{}", reference))
+            .or_else(|_| repo.find_reference(&format!("refs/tags/{}", reference)))
+            .is_ok();
+        return Ok(!is_ref);
+    }
+    let mut remote = git2::Remote::create_detached(project.fetch_url.as_str())
+        .map_err(|e| Error::Git("ls-remote", e))?;
+    let mut cb = git2::RemoteCallbacks::new();
+    let mut credentials = CredentialChain::new(ctx.credentials.clone());
+    cb.credentials(move |url, username_from_url, allowed_types| {
+        credentials.next(url, username_from_url, allowed_types)
+    });
+    let connection = remote
+        .connect_auth(git2::Direction::Fetch, Some(cb), None)
+        .map_err(|e| Error::Git("ls-remote", e))?;
+    let names = [
+        format!("refs/heads/{}", reference),
+        format!("refs/tags/{}", reference),
+    ];
+    let is_ref = connection
+        .list()
+        .map_err(|e| Error::Git("ls-remote", e))?
+        .iter()
+        .any(|head| names.iter().any(|name| name == head.name()));
+    Ok(!is_ref)
+}
+
+/// Returns true if 'reference' is a full commit id, only those can be fetched by id.
+fn is_full_commit_id(reference: &str) -> bool {
+    reference.len() == 40 && is_commit_id(reference)
+}
+
+///
+/// Resolve 'reference' as remote branch origin/<reference>, tag or commit id
+/// in that order.
+fn resolve_reference<'a>(
+    repo: &'a Repository,
+    name: &str,
+    reference: &str,
+) -> Result<(git2::AnnotatedCommit<'a>, RefKind)> {
+    let annotated = |r: git2::Reference| {
+        repo.reference_to_annotated_commit(&r)
+            .map_err(|e| Error::Git("resolve reference", e))
+    };
+    if let Ok(r) = repo.resolve_reference_from_short_name(&format!("origin/{}", reference)) {
+        return Ok((annotated(r)?, RefKind::Branch));
+    }
+    if let Ok(r) = repo.find_reference(&format!("refs/tags/{}", reference)) {
+        return Ok((annotated(r)?, RefKind::Tag));
+    }
+    if is_commit_id(reference) {
+        if let Ok(commit) = repo
+            .revparse_single(reference)
+            .and_then(|object| object.peel_to_commit())
+            .and_then(|commit| repo.find_annotated_commit(commit.id()))
+        {
+            return Ok((commit, RefKind::Commit));
+        }
+    }
+    Err(Error::ReferenceNotFound(name.into(), reference.into()))
+}
+
+/// Messages of the git command for network errors that may not happen again if retried.
+const TRANSIENT_MESSAGES: [&str; 10] = [
+    "could not resolve host",
+    "temporary failure in name resolution",
+    "connection refused",
+    "connection timed out",
+    "connection reset",
+    "operation timed out",
+    "failed to connect",
+    "early eof",
+    "the remote end hung up unexpectedly",
+    "rpc failed",
+];
+
+/// Returns true if 'message' is one of TRANSIENT_MESSAGES.
+fn is_transient_message(message: &str) -> bool {
+    let message = message.to_lowercase();
+    TRANSIENT_MESSAGES.iter().any(|m| message.contains(m))
+}
+
+/// Returns true if 'e' is a network error that may not happen again if retried.
+/// libgit2 reports socket errors such as connection refused with the Os class,
+/// which is also used for local file errors, so those must have a network message.
+fn is_transient(e: &Error) -> bool {
+    use git2::ErrorClass;
+    match e {
+        Error::Git(_, e) if e.code() == git2::ErrorCode::Auth => false,
+        Error::Git(_, e) if e.code() == git2::ErrorCode::User => false,
+        Error::Git(_, e) => match e.class() {
+            ErrorClass::Net | ErrorClass::Http | ErrorClass::Ssh | ErrorClass::Ssl => true,
+            ErrorClass::Os => is_transient_message(e.message()),
+            _ => false,
+        },
+        Error::CommandFailed(_, _, _, stderr) => is_transient_message(stderr),
+        _ => false,
+    }
+}
+
+/// Delay before the first retry, doubled for every retry up to 32 times as long.
+#[cfg(not(test))]
+const RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+#[cfg(test)]
+const RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(1);
+
+///
+/// Run the network operation 'op', on transient errors it is retried up to 'retries' times
+/// with exponential backoff, 1s, 2s, 4s... up to 32s between attempts.
+/// 'attempts' is raised to the number of attempts used, no retry is done once 'cancel' is set.
+fn retry<T, F>(
+    name: &str,
+    retries: u32,
+    attempts: &mut u32,
+    cancel: &CancelToken,
+    mut op: F,
+) -> Result<T>
+where
+    F: FnMut() -> Result<T>,
+{
+    let mut attempt = 1;
+    loop {
+        let res = op();
+        *attempts = (*attempts).max(attempt);
+        match res {
+            Ok(value) => return Ok(value),
+            // A callback aborted by the cancel token fails with a user error.
+            Err(Error::Git(_, e)) if cancel.is_cancelled() && e.code() == git2::ErrorCode::User => {
+                return Err(Error::Interrupted(name.into()))
+            }
+            Err(e) if attempt <= retries && is_transient(&e) && !cancel.is_cancelled() => {
+                let delay = RETRY_DELAY * (1 << (attempt - 1).min(5));
+                log::warn!(
+                    "{}: {}, retry {}/{} in {:?}",
+                    name,
+                    e,
+                    attempt,
+                    retries,
+                    delay
+                );
+                let now = std::time::Instant::now();
+                while now.elapsed() < delay && !cancel.is_cancelled() {
+                    let poll = std::time::Duration::from_millis(100);
+                    std::thread::sleep(poll.min(delay.saturating_sub(now.elapsed())));
+                }
+                attempt += 1;
+            }
+            Err(e) if attempt > 1 => return Err(Error::Retried(name.into(), attempt, Box::new(e))),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
 fn do_fetch<'a>(
     repo: &'a Repository,
     name: &str,
     proj: &GlProject,
-) -> Result<git2::AnnotatedCommit<'a>> {
-    let mut fopt = fetch_options(name);
+    attempts: &mut u32,
+    ctx: &SyncContext,
+) -> Result<(git2::AnnotatedCommit<'a>, RefKind)> {
+    let retries = proj.fetch_retries.unwrap_or_default();
     let refs: Vec<&str> = vec![];
-    repo.find_remote("origin")
-        .and_then(|mut remote| remote.fetch(&refs, Some(&mut fopt), None))
-        .and_then(|_| {
-            repo.resolve_reference_from_short_name(&format!("origin/{}", &proj.reference))
-        })
-        .and_then(|ref_head| repo.reference_to_annotated_commit(&ref_head))
-        .map_err(|e| Error::Git("fetch reference", e))
+    let mut remote = repo
+        .find_remote("origin")
+        .map_err(|e| Error::Git("fetch reference", e))?;
+    retry(name, retries, attempts, &ctx.cancel, || {
+        let mut fopt = fetch_options(name, ctx);
+        if let Some(depth) = proj.depth {
+            // Only keep shallow repositories shallow, a full repository is never truncated.
+            if repo.is_shallow() {
+                fopt.depth(depth as i32);
+            }
+        }
+        remote
+            .fetch(&refs, Some(&mut fopt), None)
+            .map_err(|e| Error::Git("fetch reference", e))
+    })?;
+    match resolve_reference(repo, name, &proj.reference) {
+        Err(Error::ReferenceNotFound(..)) if is_full_commit_id(&proj.reference) => {
+            // Commit is not reachable from any branch or tag, ask for it explicitly.
+            retry(name, retries, attempts, &ctx.cancel, || {
+                let mut fopt = fetch_options(name, ctx);
+                remote
+                    .fetch(&[proj.reference.as_str()], Some(&mut fopt), None)
+                    .map_err(|e| Error::Git("fetch commit", e))
+            })?;
+            resolve_reference(repo, name, &proj.reference)
+        }
+        res => res,
+    }
+}
+
+/// Checkout 'commit' as a detached HEAD.
+fn checkout_detached(repo: &Repository, commit: &git2::AnnotatedCommit) -> Result<()> {
+    if repo.head_detached().unwrap_or(false)
+        && repo.head().ok().and_then(|head| head.target()) == Some(commit.id())
+    {
+        log::info!("Already at: {}", commit.id());
+        return Ok(());
+    }
+    let obj = repo
+        .find_object(commit.id(), None)
+        .map_err(|e| Error::Git("find commit", e))?;
+    repo.checkout_tree(&obj, Some(git2::build::CheckoutBuilder::default().safe()))
+        .map_err(|e| Error::Git("checkout tree", e))?;
+    repo.set_head_detached(commit.id())
+        .map_err(|e| Error::Git("set head", e))?;
+    log::info!("Detached HEAD at: {}", commit.id());
+    Ok(())
+}
+
+/// Update the working tree to the fetched commit.
+/// Branches are merged using the sync strategy, tags and commits are checked out detached.
+fn do_update<'a>(
+    repo: &'a Repository,
+    project: &GlProject,
+    fetch_commit: git2::AnnotatedCommit<'a>,
+    kind: RefKind,
+    reporter: &dyn Reporter,
+) -> Result<()> {
+    reporter.report(&Event::Checkout {
+        project: project.name.clone(),
+        commit: fetch_commit.id().to_string(),
+    });
+    match kind {
+        RefKind::Branch => do_merge(repo, project, fetch_commit),
+        RefKind::Tag | RefKind::Commit => checkout_detached(repo, &fetch_commit),
+    }
+}
+
+/// Create the remote or update its URLs if they differ from 'remote'.
+fn set_remote(repo: &Repository, remote: &GitRemote) -> Result<()> {
+    let map_err = |e| Error::Git("remote", e);
+    let (url, pushurl) = match repo.find_remote(&remote.name) {
+        Ok(r) => (r.url().map(String::from), r.pushurl().map(String::from)),
+        Err(_) => {
+            repo.remote(&remote.name, &remote.fetch_url)
+                .map_err(map_err)?;
+            (Some(remote.fetch_url.clone()), None)
+        }
+    };
+    if url.as_deref() != Some(remote.fetch_url.as_str()) {
+        log::info!("Remote: {} set URL to: {}", remote.name, remote.fetch_url);
+        repo.remote_set_url(&remote.name, &remote.fetch_url)
+            .map_err(map_err)?;
+    }
+    if pushurl != remote.push_url {
+        repo.remote_set_pushurl(&remote.name, remote.push_url.as_deref())
+            .map_err(map_err)?;
+    }
+    Ok(())
+}
+
+/// Configure origin and the extra remotes of the project with URLs from the manifest.
+fn configure_remotes(repo: &Repository, project: &GlProject) -> Result<()> {
+    let origin = GitRemote {
+        name: String::from("origin"),
+        fetch_url: project.fetch_url.clone(),
+        push_url: Some(project.push_url.clone()).filter(|url| !url.is_empty()),
+    };
+    set_remote(repo, &origin)?;
+    for remote in &project.git_remotes {
+        set_remote(repo, remote)?;
+    }
+    Ok(())
 }
 
 fn fast_forward(
@@ -60,108 +437,586 @@ fn fast_forward(
         None => String::from_utf8_lossy(lb.name_bytes()).to_string(),
     };
     let msg = format!("Fast-Forward: Setting {} to id: {}", name, rc.id());
-    println!("{}", msg);
-    lb.set_target(rc.id(), &msg)
-        .map_err(|e| Error::Git("Set target", e))?;
-    repo.set_head(&name)
+    log::info!("{}", msg);
+    checkout_commit(repo, &name, rc.id(), &msg)
+}
+
+/// Returns the user signature from git config or a glrepo signature as fallback.
+fn signature(repo: &Repository) -> Result<git2::Signature<'static>> {
+    repo.signature()
+        .or_else(|_| git2::Signature::now("glrepo", "glrepo@localhost"))
+        .map_err(|e| Error::Git("signature", e))
+}
+
+/// Returns the paths of all conflicting entries in the index.
+fn conflicts(index: &git2::Index) -> Result<Vec<String>> {
+    let mut files = vec![];
+    for conflict in index.conflicts().map_err(|e| Error::Git("conflicts", e))? {
+        let conflict = conflict.map_err(|e| Error::Git("conflicts", e))?;
+        if let Some(entry) = conflict.our.or(conflict.their).or(conflict.ancestor) {
+            files.push(String::from_utf8_lossy(&entry.path).to_string());
+        }
+    }
+    Ok(files)
+}
+
+///
+/// Checkout commit 'oid' and move branch 'refname' and HEAD to it.
+/// The working directory is updated before the branch is moved
+/// so a failed checkout leaves the repository as it was.
+fn checkout_commit(repo: &Repository, refname: &str, oid: git2::Oid, msg: &str) -> Result<()> {
+    let commit = repo
+        .find_commit(oid)
+        .map_err(|e| Error::Git("find commit", e))?;
+    repo.checkout_tree(
+        commit.as_object(),
+        Some(git2::build::CheckoutBuilder::default().safe()),
+    )
+    .map_err(|e| Error::Git("checkout tree", e))?;
+    repo.reference(refname, oid, true, msg)
+        .map_err(|e| Error::Git("Reference", e))?;
+    repo.set_head(refname)
         .map_err(|e| Error::Git("set head", e))?;
-    repo.checkout_head(Some(
-        git2::build::CheckoutBuilder::default()
-            // For some reason the force is required to make the working directory actually get updated
-            // I suspect we should be adding some logic to handle dirty working directory states
-            // but this is just an example so maybe not.
-            .force(),
-    ))
-    .map_err(|e| Error::Git("checkout head", e))?;
     Ok(())
 }
 
+///
+/// Rebase the local commits of branch 'refname' on top of 'fetch_commit'.
+/// The rebase is done in memory, on conflicts it is aborted
+/// and the repository is left untouched.
+fn rebase(
+    repo: &Repository,
+    project: &GlProject,
+    refname: &str,
+    fetch_commit: &git2::AnnotatedCommit,
+) -> Result<()> {
+    let local = repo
+        .find_reference(refname)
+        .and_then(|local| repo.reference_to_annotated_commit(&local))
+        .map_err(|e| Error::Git("rebase", e))?;
+    let mut opts = git2::RebaseOptions::new();
+    opts.inmemory(true);
+    let mut rebase = repo
+        .rebase(Some(&local), Some(fetch_commit), None, Some(&mut opts))
+        .map_err(|e| Error::Git("rebase", e))?;
+    let sig = signature(repo)?;
+    let mut last = fetch_commit.id();
+    while let Some(op) = rebase.next() {
+        let res = op
+            .and_then(|_| rebase.inmemory_index())
+            .map_err(|e| Error::Git("rebase", e))
+            .and_then(|index| {
+                if index.has_conflicts() {
+                    return Err(Error::SyncConflict(
+                        project.name.clone(),
+                        conflicts(&index)?,
+                    ));
+                }
+                match rebase.commit(None, &sig, None) {
+                    Ok(oid) => last = oid,
+                    // Patch is already upstream
+                    Err(e) if e.code() == git2::ErrorCode::Applied => {}
+                    Err(e) => return Err(Error::Git("rebase commit", e)),
+                }
+                Ok(())
+            });
+        if let Err(e) = res {
+            rebase.abort().ok();
+            return Err(e);
+        }
+    }
+    rebase
+        .finish(Some(&sig))
+        .map_err(|e| Error::Git("rebase finish", e))?;
+    let msg = format!("Rebase: Setting {} to id: {}", refname, last);
+    log::info!("{}", msg);
+    checkout_commit(repo, refname, last, &msg)
+}
+
+///
+/// Create a merge commit of branch 'refname' and 'fetch_commit'.
+/// The merge is done in memory, on conflicts the repository is left untouched.
+fn merge(
+    repo: &Repository,
+    project: &GlProject,
+    refname: &str,
+    fetch_commit: &git2::AnnotatedCommit,
+) -> Result<()> {
+    let ours = repo
+        .find_reference(refname)
+        .and_then(|local| local.peel_to_commit())
+        .map_err(|e| Error::Git("merge", e))?;
+    let theirs = repo
+        .find_commit(fetch_commit.id())
+        .map_err(|e| Error::Git("merge", e))?;
+    let mut index = repo
+        .merge_commits(&ours, &theirs, None)
+        .map_err(|e| Error::Git("merge", e))?;
+    if index.has_conflicts() {
+        return Err(Error::SyncConflict(
+            project.name.clone(),
+            conflicts(&index)?,
+        ));
+    }
+    let tree = index
+        .write_tree_to(repo)
+        .and_then(|id| repo.find_tree(id))
+        .map_err(|e| Error::Git("merge write tree", e))?;
+    let sig = signature(repo)?;
+    let msg = format!("Merge upstream {} into {}", project.reference, refname);
+    let oid = repo
+        .commit(None, &sig, &sig, &msg, &tree, &[&ours, &theirs])
+        .map_err(|e| Error::Git("merge commit", e))?;
+    log::info!("{}", msg);
+    checkout_commit(repo, refname, oid, &msg)
+}
+
+/// Throw away local commits and changes and reset branch to 'fetch_commit'.
+fn reset_hard(
+    repo: &Repository,
+    refname: &str,
+    fetch_commit: &git2::AnnotatedCommit,
+) -> Result<()> {
+    let msg = format!("Reset: Setting {} to id: {}", refname, fetch_commit.id());
+    log::info!("{}", msg);
+    let obj = repo
+        .find_object(fetch_commit.id(), None)
+        .map_err(|e| Error::Git("reset", e))?;
+    repo.reference(refname, fetch_commit.id(), true, &msg)
+        .map_err(|e| Error::Git("Reference", e))?;
+    repo.set_head(refname)
+        .map_err(|e| Error::Git("set head", e))?;
+    repo.reset(&obj, git2::ResetType::Hard, None)
+        .map_err(|e| Error::Git("reset", e))
+}
+
+///
+/// Error::OtherBranch if HEAD is on another local branch than 'refname'.
+/// A detached HEAD is fine, sync checks out the branch.
+fn check_head(repo: &Repository, project: &GlProject, refname: &str) -> Result<()> {
+    match repo.head() {
+        Ok(head) if head.is_branch() && head.name() != Some(refname) => Err(Error::OtherBranch(
+            project.name.clone(),
+            head.shorthand().unwrap_or_default().into(),
+            project.reference.clone(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+///
+/// Update local branch 'reference' with 'fetch_commit' using the sync strategy.
+/// The branch is created if it does not exist, if HEAD is on another branch nothing is done.
 fn do_merge<'a>(
     repo: &'a Repository,
-    remote_branch: &str,
+    project: &GlProject,
     fetch_commit: git2::AnnotatedCommit<'a>,
 ) -> Result<()> {
-    // 1. do a merge analysis
+    let strategy = project.sync_strategy.unwrap_or_default();
+    let refname = format!("refs/heads/{}", project.reference);
+    let Ok(mut local) = repo.find_reference(&refname) else {
+        // The branch doesn't exist so just set the reference to the
+        // commit directly, usually this is right after clone.
+        return checkout_commit(
+            repo,
+            &refname,
+            fetch_commit.id(),
+            &format!("Setting {} to {}", project.reference, fetch_commit.id()),
+        );
+    };
+    check_head(repo, project, &refname)?;
+
+    // 1. do a merge analysis of the branch, not of HEAD
     let analysis = repo
-        .merge_analysis(&[&fetch_commit])
+        .merge_analysis_for_ref(&local, &[&fetch_commit])
         .map_err(|e| Error::Git("do_merge", e))?;
 
+    // Local commits on top of upstream are only thrown away by reset-hard.
+    let ahead = analysis.0.is_up_to_date() && local.target() != Some(fetch_commit.id());
+
     // 2. Do the appropriate merge
-    if analysis.0.is_fast_forward() {
+    if analysis.0.is_up_to_date() && !(ahead && strategy == SyncStrategy::ResetHard) {
+        log::info!("Already up to date");
+        if !repo
+            .head()
+            .is_ok_and(|head| head.name() == Some(refname.as_str()))
+        {
+            // HEAD is detached, checkout the branch.
+            let oid = local.target().ok_or_else(|| {
+                Error::ReferenceNotFound(project.name.clone(), project.reference.clone())
+            })?;
+            checkout_commit(repo, &refname, oid, &format!("Checkout {}", refname))?;
+        }
+    } else if analysis.0.is_fast_forward() {
         log::info!("Doing a fast forward");
-        // do a fast forward
-        let refname = format!("refs/heads/{}", remote_branch);
-        match repo.find_reference(&refname) {
-            Ok(mut r) => {
-                fast_forward(repo, &mut r, &fetch_commit)?;
-            }
-            Err(_) => {
-                // The branch doesn't exist so just set the reference to the
-                // commit directly. Usually this is because you are pulling
-                // into an empty repository.
-                repo.reference(
-                    &refname,
-                    fetch_commit.id(),
-                    true,
-                    &format!("Setting {} to {}", remote_branch, fetch_commit.id()),
-                )
-                .map_err(|e| Error::Git("Reference", e))?;
-                repo.set_head(&refname)
-                    .map_err(|e| Error::Git("set head", e))?;
-                repo.checkout_head(Some(
-                    git2::build::CheckoutBuilder::default()
-                        .allow_conflicts(true)
-                        .conflict_style_merge(true)
-                        .force(),
-                ))
-                .map_err(|e| Error::Git("checkout head", e))?;
+        fast_forward(repo, &mut local, &fetch_commit)?;
+    } else if analysis.0.is_normal() || ahead {
+        log::info!("Branch has diverged, using sync strategy: {}", strategy);
+        match strategy {
+            SyncStrategy::Rebase => rebase(repo, project, &refname, &fetch_commit)?,
+            SyncStrategy::Merge => merge(repo, project, &refname, &fetch_commit)?,
+            SyncStrategy::ResetHard => reset_hard(repo, &refname, &fetch_commit)?,
+            SyncStrategy::FfOnly => {
+                return Err(Error::SyncDiverged(project.name.clone(), strategy))
             }
-        };
-    } else if analysis.0.is_normal() {
-        // do a normal merge
-        return Err(Error::NotSupported(
-            "Git merge: Only fast forward is supported",
-        ));
-    } else {
+        }
     }
     Ok(())
 }
 
-fn fetch_options(project_name: &str) -> FetchOptions<'static> {
+/// Returns true if 'line' from the git command is a progress line.
+fn is_progress(line: &str) -> bool {
+    line.contains("% (")
+}
+
+///
+/// Parse a progress line of the git command, "Receiving objects:  45% (450/1000), 1.20 MiB | ..."
+/// or "Resolving deltas: 100% (20/20), done.", other progress lines return None.
+fn parse_progress(project: &str, line: &str) -> Option<Event> {
+    let (what, rest) = line.split_once(':')?;
+    let (_, rest) = rest.split_once('(')?;
+    let (counts, rest) = rest.split_once(')')?;
+    let (done, total) = counts.split_once('/')?;
+    let done: usize = done.trim().parse().ok()?;
+    let total: usize = total.trim().parse().ok()?;
+    match what.trim() {
+        "Receiving objects" => Some(Event::Transfer {
+            project: project.into(),
+            received_objects: done,
+            indexed_objects: done,
+            total_objects: total,
+            received_bytes: parse_bytes(rest).unwrap_or_default(),
+        }),
+        "Resolving deltas" => Some(Event::Deltas {
+            project: project.into(),
+            indexed_deltas: done,
+            total_deltas: total,
+        }),
+        _ => None,
+    }
+}
+
+/// Parse the received size, ", 1.20 MiB | 2.00 MiB/s" of a progress line.
+fn parse_bytes(s: &str) -> Option<usize> {
+    let size = s.trim_start_matches(',').split('|').next()?.trim();
+    let (value, unit) = size.split_once(' ')?;
+    let value: f64 = value.parse().ok()?;
+    let scale = match unit {
+        "bytes" => 1,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        _ => return None,
+    };
+    Some((value * scale as f64) as usize)
+}
+
+///
+/// Run the git command in 'dir' with the credentials of 'ctx'.
+/// Progress is sent to the reporter of 'ctx' and git is terminated when it is cancelled.
+fn run_git(project: &GlProject, dir: &Path, args: &[&str], ctx: &SyncContext) -> Result<()> {
+    let (mut git_args, env) = credentials::git_cli_config(&ctx.credentials);
+    git_args.extend(args.iter().map(|arg| arg.to_string()));
+    let name = project.name.clone();
+    let reporter = ctx.reporter.clone();
+    process::spawn_and_wait(
+        &project.name,
+        dir,
+        "git",
+        &git_args,
+        &env,
+        &ctx.cancel,
+        move |line| {
+            if !is_progress(line) {
+                log::info!("{}: {}", name, line);
+                return false;
+            }
+            if let Some(event) = parse_progress(&name, line) {
+                reporter.report(&event);
+            }
+            true
+        },
+    )
+}
+
+/// Run git in the project directory.
+fn git_cli(project: &GlProject, args: &[&str], ctx: &SyncContext) -> Result<()> {
+    run_git(project, &project.path, args, ctx)
+}
+
+/// Returns true if sparse checkout is enabled in the repository.
+/// git sets it in config.worktree, which libgit2 does not read by itself.
+fn is_sparse(repo: &Repository) -> bool {
+    let worktree = repo.path().join("config.worktree");
+    git2::Config::open(&worktree)
+        .and_then(|config| config.get_bool("core.sparseCheckout"))
+        .or_else(|_| {
+            repo.config()
+                .and_then(|config| config.get_bool("core.sparseCheckout"))
+        })
+        .unwrap_or(false)
+}
+
+/// Directories checked out by a cone mode sparse checkout.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct SparseCone {
+    /// Directories checked out with all their subdirectories.
+    recursive: Vec<String>,
+    /// Directories where only the files directly in them are checked out.
+    parents: Vec<String>,
+}
+
+impl SparseCone {
+    ///
+    /// Parse the patterns of .git/info/sparse-checkout written by git in cone mode.
+    /// Returns None if a pattern is not a cone mode pattern.
+    fn parse(patterns: &str) -> Option<Self> {
+        let mut dirs = vec![];
+        let mut parents = vec![];
+        for line in patterns
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        {
+            if line == "/*" || line == "!/*/" {
+                continue;
+            }
+            if let Some(dir) = line.strip_prefix("!/").and_then(|l| l.strip_suffix("/*/")) {
+                parents.push(dir.to_string());
+            } else if let Some(dir) = line.strip_prefix('/').and_then(|l| l.strip_suffix('/')) {
+                dirs.push(dir.to_string());
+            } else {
+                return None;
+            }
+        }
+        let (parents, recursive) = dirs.into_iter().partition(|d| parents.contains(d));
+        Some(Self { recursive, parents })
+    }
+
+    /// Returns the cone of the sparse checkout of 'repo', None if it is not a cone mode sparse checkout.
+    fn of(repo: &Repository) -> Option<Self> {
+        if !is_sparse(repo) {
+            return None;
+        }
+        std::fs::read_to_string(repo.path().join("info").join("sparse-checkout"))
+            .ok()
+            .and_then(|patterns| Self::parse(&patterns))
+    }
+
+    /// Returns true if the file 'path' is checked out.
+    fn contains(&self, path: &str) -> bool {
+        match path.rsplit_once('/') {
+            // Files in the root are always checked out.
+            None => true,
+            Some((dir, _)) => {
+                self.parents.iter().any(|p| p == dir)
+                    || self.recursive.iter().any(|r| {
+                        path.strip_prefix(r.as_str())
+                            .is_some_and(|p| p.starts_with('/'))
+                    })
+            }
+        }
+    }
+}
+
+///
+/// Returns true if the project must be synced with the git command.
+/// libgit2 does not support partial clones or sparse checkouts.
+fn needs_cli(repo: Option<&Repository>, project: &GlProject) -> bool {
+    project.filter.is_some() || !project.sparse.is_empty() || repo.map(is_sparse).unwrap_or(false)
+}
+
+/// Clone a partial or sparse repository using the git command.
+fn cli_clone(project: &GlProject, attempts: &mut u32, ctx: &SyncContext) -> Result<()> {
+    let filter = project.filter.map(|f| format!("--filter={}", f));
+    let depth = project.depth.map(|d| format!("--depth={}", d));
+    let path = project.path.to_string_lossy();
+    let mut args = vec!["clone", "--progress"];
+    if let Some(filter) = &filter {
+        args.push(filter);
+    }
+    if let Some(depth) = &depth {
+        args.push(depth);
+    }
+    if !project.sparse.is_empty() {
+        args.push("--sparse");
+    }
+    let retries = project.fetch_retries.unwrap_or_default();
+    // A branch or tag named like a commit id must be cloned by name, a shallow clone
+    // only fetches the branch given.
+    let is_commit = retry(&project.name, retries, attempts, &ctx.cancel, || {
+        is_commit_reference(project, ctx)
+    })?;
+    if !is_commit {
+        args.extend(["--branch", project.reference.as_str()]);
+    }
+    args.extend(["--", project.fetch_url.as_str(), &path]);
+    retry(&project.name, retries, attempts, &ctx.cancel, || {
+        run_git(project, Path::new("."), &args, ctx)
+    })
+}
+
+/// Set the cone mode sparse checkout patterns or disable sparse checkout
+/// if the project no longer has any patterns.
+fn cli_sparse_checkout(repo: &Repository, project: &GlProject, ctx: &SyncContext) -> Result<()> {
+    if project.sparse.is_empty() {
+        if is_sparse(repo) {
+            return git_cli(project, &["sparse-checkout", "disable"], ctx);
+        }
+        return Ok(());
+    }
+    let mut args = vec!["sparse-checkout", "set", "--cone", "--"];
+    args.extend(project.sparse.iter().map(|p| p.as_str()));
+    git_cli(project, &args, ctx)
+}
+
+///
+/// After the git command failed to rebase or merge: abort it and return
+/// Error::SyncConflict if it stopped due to conflicts, else 'error'.
+fn cli_abort(
+    repo: &Repository,
+    project: &GlProject,
+    operation: &str,
+    error: Error,
+    ctx: &SyncContext,
+) -> Error {
+    if repo.state() == git2::RepositoryState::Clean {
+        return error;
+    }
+    let files = repo
+        .index()
+        .and_then(|mut index| index.read(true).map(|_| index))
+        .map_err(|e| Error::Git("index", e))
+        .and_then(|index| conflicts(&index));
+    if let Err(e) = git_cli(project, &[operation, "--abort"], ctx) {
+        return e;
+    }
+    match files {
+        Ok(files) if !files.is_empty() => Error::SyncConflict(project.name.clone(), files),
+        _ => error,
+    }
+}
+
+///
+/// Fetch and update a partial or sparse repository using the git command.
+/// Objects omitted by the filter are fetched on demand by git,
+/// something libgit2 can not do.
+/// What to do is decided by a merge analysis as for libgit2, not from the messages of git.
+fn cli_update(
+    repo: &Repository,
+    project: &GlProject,
+    attempts: &mut u32,
+    ctx: &SyncContext,
+) -> Result<()> {
+    cli_sparse_checkout(repo, project, ctx)?;
+    let filter = project.filter.map(|f| format!("--filter={}", f));
+    let depth = project.depth.map(|d| format!("--depth={}", d));
+    let mut args = vec!["fetch", "--progress", "--tags"];
+    if let Some(filter) = &filter {
+        args.push(filter);
+    }
+    if let (Some(depth), true) = (&depth, repo.is_shallow()) {
+        args.push(depth);
+    }
+    args.push("origin");
+    let retries = project.fetch_retries.unwrap_or_default();
+    retry(&project.name, retries, attempts, &ctx.cancel, || {
+        git_cli(project, &args, ctx)
+    })?;
+    let (fetch_commit, kind) = match resolve_reference(repo, &project.name, &project.reference) {
+        Err(Error::ReferenceNotFound(..)) if is_full_commit_id(&project.reference) => {
+            retry(&project.name, retries, attempts, &ctx.cancel, || {
+                git_cli(
+                    project,
+                    &["fetch", "--progress", "origin", &project.reference],
+                    ctx,
+                )
+            })?;
+            resolve_reference(repo, &project.name, &project.reference)?
+        }
+        res => res?,
+    };
+    let id = fetch_commit.id().to_string();
+    ctx.reporter.report(&Event::Checkout {
+        project: project.name.clone(),
+        commit: id.clone(),
+    });
+    if kind != RefKind::Branch {
+        return git_cli(project, &["checkout", "--detach", &id], ctx);
+    }
+
+    let upstream = format!("origin/{}", project.reference);
+    let refname = format!("refs/heads/{}", project.reference);
+    let Ok(local) = repo.find_reference(&refname) else {
+        return git_cli(
+            project,
+            &["checkout", "-b", &project.reference, "--track", &upstream],
+            ctx,
+        );
+    };
+    check_head(repo, project, &refname)?;
+    git_cli(project, &["checkout", &project.reference], ctx)?;
+    let strategy = project.sync_strategy.unwrap_or_default();
+    let analysis = repo
+        .merge_analysis_for_ref(&local, &[&fetch_commit])
+        .map_err(|e| Error::Git("do_merge", e))?;
+    let ahead = analysis.0.is_up_to_date() && local.target() != Some(fetch_commit.id());
+    if analysis.0.is_up_to_date() && !(ahead && strategy == SyncStrategy::ResetHard) {
+        log::info!("Already up to date");
+        return Ok(());
+    }
+    if analysis.0.is_fast_forward() {
+        return git_cli(project, &["merge", "--ff-only", &upstream], ctx);
+    }
+    log::info!("Branch has diverged, using sync strategy: {}", strategy);
+    match strategy {
+        SyncStrategy::FfOnly => Err(Error::SyncDiverged(project.name.clone(), strategy)),
+        SyncStrategy::Rebase => git_cli(project, &["rebase", &upstream], ctx)
+            .map_err(|e| cli_abort(repo, project, "rebase", e, ctx)),
+        SyncStrategy::Merge => git_cli(project, &["merge", "--no-edit", &upstream], ctx)
+            .map_err(|e| cli_abort(repo, project, "merge", e, ctx)),
+        SyncStrategy::ResetHard => git_cli(project, &["reset", "--hard", &upstream], ctx),
+    }
+}
+
+/// Min time between two transfer progress events.
+const PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+fn fetch_options(project_name: &str, ctx: &SyncContext) -> FetchOptions<'static> {
     let mut cb = git2::RemoteCallbacks::new();
-    let project_name = project_name.to_string();
+    let project = project_name.to_string();
+    let reporter = ctx.reporter.clone();
+    let cancel = ctx.cancel.clone();
+    let mut last_event: Option<std::time::Instant> = None;
     cb.transfer_progress(move |stats| {
-        if stats.received_objects() == stats.total_objects() {
-            print!(
-                "{}: Resolving deltas \x1b[0K{}/{}\r",
-                project_name,
-                stats.indexed_deltas(),
-                stats.total_deltas()
-            );
-        } else if stats.total_objects() > 0 {
-            print!(
-                "{}: Received \x1b[0K{}/{} objects ({}) in {} bytes\r",
-                project_name,
-                stats.received_objects(),
-                stats.total_objects(),
-                stats.indexed_objects(),
-                stats.received_bytes()
-            );
+        let received = stats.received_objects() == stats.total_objects();
+        let done = received && stats.indexed_deltas() == stats.total_deltas();
+        // libgit2 calls this for every received packet, only report the progress now and then.
+        if done || last_event.is_none_or(|t| t.elapsed() >= PROGRESS_INTERVAL) {
+            last_event = Some(std::time::Instant::now());
+            if received {
+                reporter.report(&Event::Deltas {
+                    project: project.clone(),
+                    indexed_deltas: stats.indexed_deltas(),
+                    total_deltas: stats.total_deltas(),
+                });
+            } else if stats.total_objects() > 0 {
+                reporter.report(&Event::Transfer {
+                    project: project.clone(),
+                    received_objects: stats.received_objects(),
+                    indexed_objects: stats.indexed_objects(),
+                    total_objects: stats.total_objects(),
+                    received_bytes: stats.received_bytes(),
+                });
+            }
         }
-        std::io::stdout().flush().ok();
-        true
+        // Returning false aborts the transfer.
+        !cancel.is_cancelled()
     });
 
-    cb.credentials(|_url, username_from_url, _allowed_types| {
-        Cred::ssh_key(
-            username_from_url.unwrap_or(""),
-            None,
-            std::path::Path::new(&format!(
-                "{}/.ssh/id_ed25519",
-                std::env::var("HOME").unwrap_or_default()
-            )),
-            None,
-        )
+    let mut credentials = CredentialChain::new(ctx.credentials.clone());
+    cb.credentials(move |url, username_from_url, allowed_types| {
+        credentials.next(url, username_from_url, allowed_types)
     });
 
     let mut fopt = git2::FetchOptions::new();
@@ -188,7 +1043,7 @@ impl Git {
 
     /// 'remote_name' Remote name example: "origin"
     /// 'fetch_url' Fetch URL.
-    pub fn remote(&self, remote_name: &str, fetch_url: &str) -> Result<git2::Remote> {
+    pub fn remote(&self, remote_name: &str, fetch_url: &str) -> Result<git2::Remote<'_>> {
         self.repo
             .remote(remote_name, fetch_url)
             .map_err(|e| Error::Git("", e))
@@ -198,29 +1053,236 @@ impl Git {
     /// Sync with upstream
     /// Doing clone path not exists
     /// Doing fetch if exists
+    /// If the working tree has local changes 'on_dirty' decides what to do.
+    /// Fetches failing with a network error are retried project.fetch_retries times.
+    ///
+    /// Progress is sent to the reporter of 'ctx'.
     ///
-    /// Return git object or an Error
-    pub fn sync(project_name: &str, project: &GlProject) -> Result<()> {
-        print!("\n{}: Syncing...\r", project_name);
+    /// Return SyncReport or an Error
+    pub fn sync(
+        project_name: &str,
+        project: &GlProject,
+        on_dirty: OnDirty,
+        ctx: &SyncContext,
+    ) -> Result<SyncReport> {
+        let reporter = &ctx.reporter;
+        let mut status = SyncStatus::Synced;
+        let mut attempts = 1;
         if project.path.exists() {
+            let mut git = Self::open(&project.path)?;
+            configure_remotes(&git.repo, project)?;
+            if git.changed()?.is_dirty() {
+                match on_dirty {
+                    OnDirty::Skip => {
+                        return Ok(SyncReport {
+                            status: SyncStatus::SkippedDirty,
+                            attempts,
+                        })
+                    }
+                    OnDirty::Abort => return Err(Error::DirtyWorkingTree(project_name.into())),
+                    OnDirty::Stash => {
+                        git.stash(project_name)?;
+                        status = SyncStatus::Stashed;
+                    }
+                }
+            }
+            reporter.report(&Event::Phase {
+                project: project_name.into(),
+                phase: Phase::Fetch,
+            });
+            let res = if needs_cli(Some(&git.repo), project) {
+                cli_update(&git.repo, project, &mut attempts, ctx)
+            } else {
+                do_fetch(&git.repo, project_name, project, &mut attempts, ctx).and_then(
+                    |(fetch_commit, kind)| {
+                        do_update(&git.repo, project, fetch_commit, kind, reporter.as_ref())
+                    },
+                )
+            };
+            if status == SyncStatus::Stashed {
+                match (&res, git.stash_pop(project_name)) {
+                    (_, Ok(())) => {}
+                    // The error that made sync fail is returned, the stash is kept.
+                    (Err(_), Err(e)) => log::error!("{}", e),
+                    (Ok(_), Err(e)) => return Err(e),
+                }
+            }
+            res?;
+        } else if needs_cli(None, project) {
+            reporter.report(&Event::Phase {
+                project: project_name.into(),
+                phase: Phase::Clone,
+            });
+            cli_clone(project, &mut attempts, ctx)?;
             let git = Self::open(&project.path)?;
-            let fetch_commit = do_fetch(&git.repo, project_name, project)?;
-            do_merge(&git.repo, &project.reference, fetch_commit)?;
+            configure_remotes(&git.repo, project)?;
+            cli_update(&git.repo, project, &mut attempts, ctx)?;
         } else {
-            let fops = fetch_options(project_name);
-            let co = CheckoutBuilder::new();
-            let mut builder = git2::build::RepoBuilder::new();
-            builder.fetch_options(fops).with_checkout(co);
-            let repo = builder
-                .clone(&project.fetch_url, &project.path)
-                .map_err(|e| Error::Git("clone", e))?;
-            let fetch_commit = do_fetch(&repo, project_name, project)?;
-            do_merge(&repo, &project.reference, fetch_commit)?;
+            let retries = project.fetch_retries.unwrap_or_default();
+            reporter.report(&Event::Phase {
+                project: project_name.into(),
+                phase: Phase::Clone,
+            });
+            let repo = retry(project_name, retries, &mut attempts, &ctx.cancel, || {
+                let mut fops = fetch_options(project_name, ctx);
+                if let Some(depth) = project.depth {
+                    fops.depth(depth as i32);
+                }
+                let co = CheckoutBuilder::new();
+                let mut builder = git2::build::RepoBuilder::new();
+                builder.fetch_options(fops).with_checkout(co);
+                builder
+                    .clone(&project.fetch_url, &project.path)
+                    .map_err(|e| Error::Git("clone", e))
+            })?;
+            configure_remotes(&repo, project)?;
+            let (fetch_commit, kind) = do_fetch(&repo, project_name, project, &mut attempts, ctx)?;
+            do_update(&repo, project, fetch_commit, kind, reporter.as_ref())?;
+        }
+        Ok(SyncReport { status, attempts })
+    }
+
+    ///
+    /// Fetch the complete history of a shallow project, progress is sent to the reporter of 'ctx'.
+    pub fn unshallow(project_name: &str, project: &GlProject, ctx: &SyncContext) -> Result<()> {
+        let git = Self::open(&project.path)?;
+        if !git.repo.is_shallow() {
+            log::info!("{}: Not a shallow repository", project_name);
+            return Ok(());
+        }
+        if needs_cli(Some(&git.repo), project) {
+            return git_cli(
+                project,
+                &["fetch", "--progress", "--unshallow", "origin"],
+                ctx,
+            );
+        }
+        ctx.reporter.report(&Event::Phase {
+            project: project_name.into(),
+            phase: Phase::Fetch,
+        });
+        let mut fopt = fetch_options(project_name, ctx);
+        // GIT_FETCH_DEPTH_UNSHALLOW
+        fopt.depth(i32::MAX);
+        let refs: Vec<&str> = vec![];
+        git.repo
+            .find_remote("origin")
+            .and_then(|mut remote| remote.fetch(&refs, Some(&mut fopt), None))
+            .map_err(|e| Error::Git("unshallow", e))
+    }
+
+    /// Stash local changes of tracked files.
+    fn stash(&mut self, project_name: &str) -> Result<()> {
+        let sig = signature(&self.repo)?;
+        self.repo
+            .stash_save(
+                &sig,
+                "glrepo: sync auto stash",
+                Some(git2::StashFlags::INCLUDE_UNTRACKED),
+            )
+            .map_err(|e| Error::Git("stash", e))?;
+        log::info!("{}: Local changes stashed", project_name);
+        Ok(())
+    }
+
+    /// Re-apply the latest stash and drop it on success.
+    /// If it can not be applied without conflicts the stash is kept and
+    /// the conflicts are left in the working tree, as by git stash pop.
+    fn stash_pop(&mut self, project_name: &str) -> Result<()> {
+        let stash_err = |e| Error::StashPop(project_name.into(), e);
+        self.repo.stash_apply(0, None).map_err(stash_err)?;
+        let index = self.repo.index().map_err(stash_err)?;
+        if index.has_conflicts() {
+            let files = conflicts(&index)?;
+            return Err(Error::StashPop(
+                project_name.into(),
+                git2::Error::from_str(&format!("conflicts in: {}", files.join(", "))),
+            ));
         }
+        self.repo.stash_drop(0).map_err(stash_err)?;
+        log::info!("{}: Local changes re-applied", project_name);
         Ok(())
     }
 
-    pub fn status(&self) -> Result<Statuses> {
+    /// Returns the commit id of HEAD, the commit that is checked out.
+    pub fn head_commit(&self) -> Result<git2::Oid> {
+        self.repo
+            .head()
+            .and_then(|head| head.peel_to_commit())
+            .map(|commit| commit.id())
+            .map_err(|e| Error::Git("head", e))
+    }
+
+    ///
+    /// Detach HEAD at its commit if it is on another local branch than 'reference',
+    /// sync then checks out 'reference' as it does after a clone.
+    /// The working tree is not changed.
+    pub fn detach_from_other_branch(&self, reference: &str) -> Result<()> {
+        let head = match self.repo.head() {
+            Ok(head) if head.is_branch() && head.shorthand() != Some(reference) => head,
+            _ => return Ok(()),
+        };
+        let oid = head
+            .peel_to_commit()
+            .map(|commit| commit.id())
+            .map_err(|e| Error::Git("head", e))?;
+        self.repo
+            .set_head_detached(oid)
+            .map_err(|e| Error::Git("set head", e))
+    }
+
+    /// Returns the commit id 'reference' resolves to in the local repository.
+    pub fn resolve(&self, project_name: &str, reference: &str) -> Result<git2::Oid> {
+        resolve_reference(&self.repo, project_name, reference).map(|(commit, _)| commit.id())
+    }
+
+    ///
+    /// Compare the local repository with 'reference'.
+    /// 'reference' is resolved the same way as by sync, without fetching.
+    pub fn project_status(&mut self, project_name: &str, reference: &str) -> Result<ProjectStatus> {
+        let mut status = ProjectStatus {
+            reference: reference.into(),
+            detached: self
+                .repo
+                .head_detached()
+                .map_err(|e| Error::Git("head", e))?,
+            ..Default::default()
+        };
+        if let Ok(head) = self.repo.head() {
+            if !status.detached {
+                status.branch = head.shorthand().map(String::from);
+            }
+            if let Ok(commit) = head.peel_to_commit() {
+                status.head = Some(commit.id().to_string());
+                if let Ok(upstream) = self.resolve(project_name, reference) {
+                    let (ahead, behind) = self
+                        .repo
+                        .graph_ahead_behind(commit.id(), upstream)
+                        .map_err(|e| Error::Git("ahead behind", e))?;
+                    status.ahead = Some(ahead);
+                    status.behind = Some(behind);
+                }
+            }
+        }
+        for (_, file) in self.changed()?.iter() {
+            if *file == git2::Status::WT_NEW {
+                status.untracked += 1;
+            } else {
+                status.dirty += 1;
+            }
+        }
+        let mut stashes = 0;
+        self.repo
+            .stash_foreach(|_, _, _| {
+                stashes += 1;
+                true
+            })
+            .map_err(|e| Error::Git("stash list", e))?;
+        status.stashes = stashes;
+        Ok(status)
+    }
+
+    pub fn status(&self) -> Result<Statuses<'_>> {
         let mut opt = git2::StatusOptions::new();
         opt.show(git2::StatusShow::IndexAndWorkdir);
         opt.include_untracked(true);
@@ -229,10 +1291,28 @@ impl Git {
             .map_err(|e| Error::Git("status", e))
     }
 
+    /// Returns changed files.
+    /// Files outside the sparse checkout, marked skip-worktree or outside the cone
+    /// of the sparse-checkout patterns, are not seen as deleted.
     pub fn changed(&self) -> Result<ChangedFiles> {
         let mut files = ChangedFiles::default();
+        let index = self.repo.index().map_err(|e| Error::Git("index", e))?;
+        let cone = SparseCone::of(&self.repo);
         for entry in self.status()?.iter() {
             let status = entry.status();
+            let path = entry.path().unwrap_or_default();
+            if status == git2::Status::WT_DELETED
+                && (cone.as_ref().is_some_and(|cone| !cone.contains(path))
+                    || index
+                        .get_path(Path::new(path), 0)
+                        .map(|e| {
+                            e.flags_extended & git2::IndexEntryExtendedFlag::SKIP_WORKTREE.bits()
+                                != 0
+                        })
+                        .unwrap_or(false))
+            {
+                continue;
+            }
             files
                 .files
                 .insert(entry.path().unwrap_or("???Invalid UTF8?").into(), status);
@@ -241,3 +1321,545 @@ impl Git {
         Ok(files)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("glrepo-git-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Returns 'base' with 'blob' added as 'path', directories in 'path' are created.
+    fn insert(
+        repo: &Repository,
+        base: Option<&git2::Tree>,
+        path: &str,
+        blob: git2::Oid,
+    ) -> git2::Oid {
+        let mut builder = repo.treebuilder(base).unwrap();
+        match path.split_once('/') {
+            None => builder.insert(path, blob, 0o100644).unwrap(),
+            Some((dir, rest)) => {
+                let sub = base
+                    .and_then(|tree| tree.get_name(dir))
+                    .map(|entry| repo.find_tree(entry.id()).unwrap());
+                let id = insert(repo, sub.as_ref(), rest, blob);
+                builder.insert(dir, id, 0o040000).unwrap()
+            }
+        };
+        builder.write().unwrap()
+    }
+
+    /// Commit 'file' with 'content' to 'refname', the working tree is not touched.
+    fn commit(repo: &Repository, refname: &str, file: &str, content: &str) -> git2::Oid {
+        let parent = repo
+            .find_reference(refname)
+            .and_then(|r| r.peel_to_commit())
+            .ok();
+        let base = parent.as_ref().map(|p| p.tree().unwrap());
+        let blob = repo.blob(content.as_bytes()).unwrap();
+        let tree = insert(repo, base.as_ref(), file, blob);
+        let tree = repo.find_tree(tree).unwrap();
+        let sig = git2::Signature::now("test", "test@localhost").unwrap();
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        repo.commit(Some(refname), &sig, &sig, file, &tree, &parents)
+            .unwrap()
+    }
+
+    /// Commit on the branch of HEAD and update the working tree.
+    fn local_commit(path: &Path, file: &str, content: &str) -> git2::Oid {
+        let repo = Repository::open(path).unwrap();
+        let oid = commit(&repo, "HEAD", file, content);
+        repo.checkout_head(Some(CheckoutBuilder::new().force()))
+            .unwrap();
+        oid
+    }
+
+    /// A bare upstream repository with one commit on main and a project cloning it.
+    fn upstream(dir: &Path, strategy: SyncStrategy) -> (Repository, GlProject) {
+        let up = Repository::init_bare(dir.join("up.git")).unwrap();
+        commit(&up, "refs/heads/main", "a", "1");
+        up.set_head("refs/heads/main").unwrap();
+        let project = GlProject {
+            name: String::from("p"),
+            fetch_url: dir.join("up.git").to_string_lossy().to_string(),
+            path: dir.join("p"),
+            reference: String::from("main"),
+            sync_strategy: Some(strategy),
+            auto_sync: true,
+            ..Default::default()
+        };
+        (up, project)
+    }
+
+    fn sync(project: &GlProject) -> Result<SyncReport> {
+        Git::sync(
+            &project.name,
+            project,
+            OnDirty::Abort,
+            &SyncContext::default(),
+        )
+    }
+
+    /// HEAD commit and its parents.
+    fn head(project: &GlProject) -> (git2::Oid, Vec<git2::Oid>) {
+        let repo = Repository::open(&project.path).unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        let parents = head.parent_ids().collect();
+        (head.id(), parents)
+    }
+
+    fn read(project: &GlProject, file: &str) -> Option<String> {
+        std::fs::read_to_string(project.path.join(file)).ok()
+    }
+
+    #[test]
+    fn test_sync_fast_forward() {
+        let dir = test_dir("ff");
+        let (up, project) = upstream(&dir, SyncStrategy::FfOnly);
+        sync(&project).unwrap();
+        assert_eq!(Some(String::from("1")), read(&project, "a"));
+        let upstream = commit(&up, "refs/heads/main", "a", "2");
+        sync(&project).unwrap();
+        assert_eq!(upstream, head(&project).0);
+        assert_eq!(Some(String::from("2")), read(&project, "a"));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    /// Number of stashes in the project.
+    fn stashes(project: &GlProject) -> usize {
+        let mut repo = Repository::open(&project.path).unwrap();
+        let mut count = 0;
+        repo.stash_foreach(|_, _, _| {
+            count += 1;
+            true
+        })
+        .unwrap();
+        count
+    }
+
+    #[test]
+    fn test_sync_stash() {
+        let dir = test_dir("stash");
+        let (up, mut project) = upstream(&dir, SyncStrategy::FfOnly);
+        sync(&project).unwrap();
+        let stash = |project: &GlProject| {
+            Git::sync(
+                &project.name,
+                project,
+                OnDirty::Stash,
+                &SyncContext::default(),
+            )
+        };
+
+        // An untracked file makes the working tree dirty and is stashed.
+        std::fs::write(project.path.join("new"), "local").unwrap();
+        assert!(matches!(sync(&project), Err(Error::DirtyWorkingTree(_))));
+        commit(&up, "refs/heads/main", "b", "1");
+        assert_eq!(SyncStatus::Stashed, stash(&project).unwrap().status);
+        assert_eq!(Some(String::from("local")), read(&project, "new"));
+        assert_eq!(Some(String::from("1")), read(&project, "b"));
+        assert_eq!(0, stashes(&project));
+        std::fs::remove_file(project.path.join("new")).unwrap();
+
+        // A conflict keeps the stash and the working tree is not reset.
+        std::fs::write(project.path.join("a"), "local").unwrap();
+        let upstream = commit(&up, "refs/heads/main", "a", "2");
+        assert!(matches!(stash(&project), Err(Error::StashPop(..))));
+        assert_eq!(upstream, head(&project).0);
+        assert_eq!(1, stashes(&project));
+        assert_ne!(Some(String::from("2")), read(&project, "a"));
+
+        // A failed sync returns its own error and re-applies the local changes.
+        let mut repo = Repository::open(&project.path).unwrap();
+        repo.checkout_head(Some(CheckoutBuilder::new().force()))
+            .unwrap();
+        repo.stash_drop(0).unwrap();
+        std::fs::write(project.path.join("a"), "local").unwrap();
+        project.fetch_url = dir.join("missing.git").to_string_lossy().to_string();
+        assert!(matches!(stash(&project), Err(Error::Git(..))));
+        assert_eq!(Some(String::from("local")), read(&project, "a"));
+        assert_eq!(0, stashes(&project));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_sync_diverged() {
+        for strategy in [
+            SyncStrategy::FfOnly,
+            SyncStrategy::Rebase,
+            SyncStrategy::Merge,
+            SyncStrategy::ResetHard,
+        ] {
+            let dir = test_dir(&format!("diverged-{}", strategy));
+            let (up, project) = upstream(&dir, strategy);
+            sync(&project).unwrap();
+            let local = local_commit(&project.path, "local", "x");
+            let upstream = commit(&up, "refs/heads/main", "a", "2");
+            let res = sync(&project);
+            let (head, parents) = head(&project);
+            match strategy {
+                SyncStrategy::FfOnly => {
+                    assert!(matches!(res, Err(Error::SyncDiverged(..))));
+                    assert_eq!(local, head);
+                }
+                SyncStrategy::Rebase => {
+                    res.unwrap();
+                    assert_eq!(vec![upstream], parents);
+                    assert_eq!(Some(String::from("x")), read(&project, "local"));
+                }
+                SyncStrategy::Merge => {
+                    res.unwrap();
+                    assert_eq!(vec![local, upstream], parents);
+                    assert_eq!(Some(String::from("x")), read(&project, "local"));
+                }
+                SyncStrategy::ResetHard => {
+                    res.unwrap();
+                    assert_eq!(upstream, head);
+                    assert_eq!(None, read(&project, "local"));
+                }
+            }
+            if strategy != SyncStrategy::FfOnly {
+                assert_eq!(Some(String::from("2")), read(&project, "a"));
+            }
+            std::fs::remove_dir_all(&dir).ok();
+        }
+    }
+
+    #[test]
+    fn test_sync_ahead_of_upstream() {
+        let dir = test_dir("ahead");
+        let (_up, mut project) = upstream(&dir, SyncStrategy::FfOnly);
+        let upstream = {
+            sync(&project).unwrap();
+            head(&project).0
+        };
+        let local = local_commit(&project.path, "local", "x");
+        // Up to date, local commits are kept.
+        sync(&project).unwrap();
+        assert_eq!(local, head(&project).0);
+        // Except with reset-hard.
+        project.sync_strategy = Some(SyncStrategy::ResetHard);
+        sync(&project).unwrap();
+        assert_eq!(upstream, head(&project).0);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_sync_head_on_other_branch() {
+        let dir = test_dir("other-branch");
+        let (up, project) = upstream(&dir, SyncStrategy::Rebase);
+        sync(&project).unwrap();
+        let repo = Repository::open(&project.path).unwrap();
+        let main = head(&project).0;
+        repo.branch("feature", &repo.find_commit(main).unwrap(), false)
+            .unwrap();
+        repo.set_head("refs/heads/feature").unwrap();
+        let feature = local_commit(&project.path, "feature", "x");
+        commit(&up, "refs/heads/main", "a", "2");
+        assert!(matches!(sync(&project), Err(Error::OtherBranch(..))));
+        // Neither main nor the checked out branch is touched.
+        let target = |name: &str| repo.find_reference(name).unwrap().target();
+        assert_eq!(Some(main), target("refs/heads/main"));
+        assert_eq!(Some(feature), target("refs/heads/feature"));
+        assert_eq!(Some("refs/heads/feature"), repo.head().unwrap().name());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_is_transient() {
+        let git =
+            |code, class, message| Error::Git("fetch", git2::Error::new(code, class, message));
+        use git2::{ErrorClass, ErrorCode};
+        assert!(is_transient(&git(
+            ErrorCode::GenericError,
+            ErrorClass::Net,
+            "unexpected eof"
+        )));
+        assert!(is_transient(&git(
+            ErrorCode::GenericError,
+            ErrorClass::Http,
+            "502"
+        )));
+        assert!(is_transient(&git(
+            ErrorCode::GenericError,
+            ErrorClass::Os,
+            "failed to connect to host: Connection refused"
+        )));
+        assert!(!is_transient(&git(
+            ErrorCode::GenericError,
+            ErrorClass::Os,
+            "failed to open file: Permission denied"
+        )));
+        assert!(!is_transient(&git(
+            ErrorCode::Auth,
+            ErrorClass::Ssh,
+            "auth"
+        )));
+        assert!(!is_transient(&git(
+            ErrorCode::User,
+            ErrorClass::Net,
+            "aborted"
+        )));
+        assert!(!is_transient(&git(
+            ErrorCode::NotFound,
+            ErrorClass::Reference,
+            "not found"
+        )));
+        let command = |stderr: &str| {
+            Error::CommandFailed(
+                String::from("p"),
+                String::from("git fetch"),
+                128,
+                String::from(stderr),
+            )
+        };
+        assert!(is_transient(&command(
+            "fatal: unable to access: Could not resolve host: example.com"
+        )));
+        assert!(!is_transient(&command("fatal: repository 'x' not found")));
+        assert!(!is_transient(&Error::General(String::from(
+            "connection refused"
+        ))));
+    }
+
+    #[test]
+    fn test_retry() {
+        let cancel = CancelToken::new();
+        let transient = || {
+            Error::Git(
+                "fetch",
+                git2::Error::new(
+                    git2::ErrorCode::GenericError,
+                    git2::ErrorClass::Net,
+                    "unexpected eof",
+                ),
+            )
+        };
+
+        // Succeeds on the third attempt.
+        let mut attempts = 1;
+        let mut calls = 0;
+        let res = retry("p", 3, &mut attempts, &cancel, || {
+            calls += 1;
+            if calls < 3 {
+                Err(transient())
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(3, res.unwrap());
+        assert_eq!(3, attempts);
+
+        // Gives up after the retries.
+        let mut attempts = 1;
+        let mut calls = 0;
+        let res: Result<()> = retry("p", 2, &mut attempts, &cancel, || {
+            calls += 1;
+            Err(transient())
+        });
+        assert!(matches!(res, Err(Error::Retried(_, 3, _))));
+        assert_eq!((3, 3), (calls, attempts));
+
+        // Other errors are not retried.
+        let mut attempts = 1;
+        let mut calls = 0;
+        let res: Result<()> = retry("p", 2, &mut attempts, &cancel, || {
+            calls += 1;
+            Err(Error::General(String::from("failed")))
+        });
+        assert!(matches!(res, Err(Error::General(_))));
+        assert_eq!((1, 1), (calls, attempts));
+
+        // Nothing is retried after cancel.
+        cancel.cancel();
+        let mut attempts = 1;
+        let res: Result<()> = retry("p", 2, &mut attempts, &cancel, || Err(transient()));
+        assert!(matches!(res, Err(Error::Git(..))));
+        assert_eq!(1, attempts);
+    }
+
+    #[test]
+    fn test_retry_cancelled() {
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let mut attempts = 1;
+        let aborted = || {
+            Err::<(), _>(Error::Git(
+                "fetch",
+                git2::Error::new(git2::ErrorCode::User, git2::ErrorClass::Callback, "aborted"),
+            ))
+        };
+        assert!(matches!(
+            retry("p", 3, &mut attempts, &cancel, aborted),
+            Err(Error::Interrupted(_))
+        ));
+        assert_eq!(1, attempts);
+        assert!(matches!(
+            retry("p", 3, &mut attempts, &CancelToken::new(), aborted),
+            Err(Error::Git(..))
+        ));
+    }
+
+    #[test]
+    fn test_sparse_cone() {
+        let cone = SparseCone::parse("/*\n!/*/\n/a/\n!/a/*/\n/a/b/\n/c/\n").unwrap();
+        assert_eq!(
+            SparseCone {
+                recursive: vec![String::from("a/b"), String::from("c")],
+                parents: vec![String::from("a")],
+            },
+            cone
+        );
+        assert!(cone.contains("root.txt"));
+        assert!(cone.contains("a/file"));
+        assert!(!cone.contains("a/other/file"));
+        assert!(cone.contains("a/b/file"));
+        assert!(cone.contains("a/b/deep/file"));
+        assert!(cone.contains("c/d/file"));
+        assert!(!cone.contains("cd/file"));
+        assert!(!cone.contains("d/file"));
+        assert_eq!(None, SparseCone::parse("*.rs\n"));
+    }
+
+    #[test]
+    fn test_parse_progress() {
+        let line = "Receiving objects:  45% (450/1000), 1.50 MiB | 2.00 MiB/s";
+        match parse_progress("p", line) {
+            Some(Event::Transfer {
+                received_objects,
+                total_objects,
+                received_bytes,
+                ..
+            }) => {
+                assert_eq!(450, received_objects);
+                assert_eq!(1000, total_objects);
+                assert_eq!(3 << 19, received_bytes);
+            }
+            e => panic!("expected transfer, got: {:?}", e),
+        }
+        assert!(matches!(
+            parse_progress("p", "Resolving deltas: 100% (20/20), done."),
+            Some(Event::Deltas {
+                indexed_deltas: 20,
+                total_deltas: 20,
+                ..
+            })
+        ));
+        let remote = "remote: Counting objects: 100% (3/3), done.";
+        assert!(is_progress(remote));
+        assert!(parse_progress("p", remote).is_none());
+        assert!(!is_progress("fatal: repository not found"));
+    }
+
+    #[test]
+    fn test_sync_sparse_with_git_command() {
+        let dir = test_dir("sparse");
+        let (up, mut project) = upstream(&dir, SyncStrategy::FfOnly);
+        commit(&up, "refs/heads/main", "dir/x", "1");
+        commit(&up, "refs/heads/main", "other/y", "1");
+        project.sparse = vec![String::from("dir")];
+        let reporter = Arc::new(Events::default());
+        let ctx = SyncContext {
+            reporter: reporter.clone(),
+            ..Default::default()
+        };
+        Git::sync(&project.name, &project, OnDirty::Abort, &ctx).unwrap();
+        assert_eq!(Some(String::from("1")), read(&project, "dir/x"));
+        assert_eq!(None, read(&project, "other/y"));
+        assert!(reporter
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|e| matches!(e, Event::Checkout { .. })));
+
+        // Fast forward
+        let upstream = commit(&up, "refs/heads/main", "dir/x", "2");
+        Git::sync(&project.name, &project, OnDirty::Abort, &ctx).unwrap();
+        assert_eq!(upstream, head(&project).0);
+        assert_eq!(Some(String::from("2")), read(&project, "dir/x"));
+
+        // Diverged is decided before git is run, not from its messages.
+        local_commit(&project.path, "dir/local", "x");
+        commit(&up, "refs/heads/main", "dir/x", "3");
+        assert!(matches!(
+            Git::sync(&project.name, &project, OnDirty::Abort, &ctx),
+            Err(Error::SyncDiverged(..))
+        ));
+
+        // Files outside the cone are not changed, even without the skip-worktree flag.
+        let git = Git::open(&project.path).unwrap();
+        assert!(git.changed().unwrap().is_empty());
+        let mut index = git.repo.index().unwrap();
+        let mut entry = index.get_path(Path::new("other/y"), 0).unwrap();
+        entry.flags_extended &= !git2::IndexEntryExtendedFlag::SKIP_WORKTREE.bits();
+        index.add(&entry).unwrap();
+        index.write().unwrap();
+        assert!(git.changed().unwrap().is_empty());
+        std::fs::remove_file(project.path.join("dir/x")).unwrap();
+        let changed = git.changed().unwrap();
+        assert_eq!(
+            vec![(&String::from("dir/x"), &git2::Status::WT_DELETED)],
+            changed.iter().collect::<Vec<_>>()
+        );
+        git.repo
+            .checkout_head(Some(CheckoutBuilder::new().path("dir/x").force()))
+            .unwrap();
+
+        // Other failures keep the message of git.
+        project.fetch_url = dir.join("missing.git").to_string_lossy().to_string();
+        match Git::sync(&project.name, &project, OnDirty::Abort, &ctx) {
+            Err(Error::CommandFailed(_, command, _, stderr)) => {
+                assert!(command.contains("fetch"), "{}", command);
+                assert!(!stderr.is_empty());
+            }
+            res => panic!("expected command failed, got: {:?}", res.map(|_| ())),
+        }
+
+        // Cancelled before git is done.
+        ctx.cancel.cancel();
+        assert!(matches!(
+            Git::sync(&project.name, &project, OnDirty::Abort, &ctx),
+            Err(Error::Interrupted(..))
+        ));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_sync_hex_branch_with_git_command() {
+        let dir = test_dir("hex-branch");
+        let (up, mut project) = upstream(&dir, SyncStrategy::FfOnly);
+        let branch = commit(&up, "refs/heads/cafe123", "dir/x", "branch");
+        // A shallow clone of a file:// URL only fetches the branch it is told to.
+        project.fetch_url = format!("file://{}", dir.join("up.git").display());
+        project.reference = String::from("cafe123");
+        project.depth = Some(1);
+        project.sparse = vec![String::from("dir")];
+        assert!(!is_commit_reference(&project, &SyncContext::default()).unwrap());
+        sync(&project).unwrap();
+        assert_eq!(branch, head(&project).0);
+        assert_eq!(Some(String::from("branch")), read(&project, "dir/x"));
+
+        // Without a branch or tag of that name it is a commit id.
+        project.reference = branch.to_string()[..7].to_string();
+        assert!(is_commit_reference(&project, &SyncContext::default()).unwrap());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    /// Keeps every reported event.
+    #[derive(Default)]
+    struct Events(std::sync::Mutex<Vec<Event>>);
+
+    impl Reporter for Events {
+        fn report(&self, event: &Event) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+}
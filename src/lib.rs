@@ -0,0 +1,38 @@
+//!
+//! glrepo, a multi GIT project fetch tool.
+//!
+//! A [`Workspace`] is a manifest and the configuration directory it is loaded from,
+//! its operations return an [`Outcome`] per project instead of printing.
+//!
+//! ```no_run
+//! use glrepo::{Outcome, SyncOptions, Workspace};
+//!
+//! let workspace = Workspace::open("default.yaml", "/home/user/.config/glrepo")?;
+//! let projects = workspace.select(&[], &[]);
+//! for project in workspace.sync(projects, &SyncOptions::default())?.projects {
+//!     if let Outcome::Failed(e) = project.outcome {
+//!         eprintln!("{}: {}", project.name, e);
+//!     }
+//! }
+//! # Ok::<(), glrepo::Error>(())
+//! ```
+pub mod cancel;
+pub mod config;
+mod credentials;
+pub mod error;
+pub mod export;
+pub mod git;
+pub mod lock;
+pub mod manifest;
+pub mod manifest_repo;
+pub mod process;
+pub mod repo_xml;
+pub mod report;
+mod threadpool;
+pub mod workspace;
+
+pub use error::{Error, Failure, Result};
+pub use git::{Git, OnDirty, ProjectStatus, SyncReport, SyncStatus};
+pub use manifest::{GlProject, GlProjects, Sort};
+pub use report::{Event, Reporter};
+pub use workspace::{ForEachOptions, Outcome, Outcomes, ProjectOutcome, SyncOptions, Workspace};
@@ -0,0 +1,76 @@
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A project pinned to a commit.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct LockedProject {
+    /// Full commit id.
+    pub commit: String,
+    /// Manifest reference the commit was resolved from.
+    pub reference: String,
+    pub fetch_url: String,
+}
+
+/// Lock file with the resolved commit of every project.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct GlLock {
+    #[serde(default)]
+    pub projects: BTreeMap<String, LockedProject>,
+}
+
+impl GlLock {
+    /// Returns the path of the lock file that belongs to 'manifest'.
+    /// Example: default.yaml -> default.lock.yaml
+    pub fn path_for<P: AsRef<Path>>(manifest: &P) -> PathBuf {
+        manifest.as_ref().with_extension("lock.yaml")
+    }
+
+    /// Read a lock file.
+    /// Error
+    /// Error::Manifest
+    pub fn try_from_yaml<P: AsRef<Path>>(lock_file: &P) -> Result<Self, Error> {
+        let lock_file = lock_file.as_ref();
+        let s = fs::read_to_string(lock_file).map_err(|e| {
+            Error::Manifest(format!(
+                "Could not load lock file: '{}' cause: {} (run glrepo lock first)",
+                lock_file.display(),
+                e
+            ))
+        })?;
+        serde_yaml::from_str::<GlLock>(&s)
+            .map_err(|e| Error::Manifest(format!("'{}': {}", lock_file.display(), e)))
+    }
+
+    /// Save to a YAML file if file already exists it will be overwritten.
+    pub fn save_to_yaml<P: AsRef<Path>>(&self, lock_file: &P) -> Result<(), Error> {
+        let s = serde_yaml::to_string(self).map_err(|e| {
+            Error::Manifest(format!(
+                "Could not serialize: '{}' cause: '{}'",
+                lock_file.as_ref().display(),
+                e
+            ))
+        })?;
+        fs::write(lock_file, s).map_err(|e| {
+            Error::Manifest(format!(
+                "output to: '{}' cause: '{}'",
+                lock_file.as_ref().display(),
+                e
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_path_for() {
+        assert_eq!(
+            PathBuf::from("/home/glrepo/default.lock.yaml"),
+            GlLock::path_for(&"/home/glrepo/default.yaml")
+        );
+    }
+}
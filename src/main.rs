@@ -1,37 +1,20 @@
 mod args;
-mod error;
-mod git;
-mod manifest;
-mod process;
-mod threadpool;
+mod output;
 use args::{Args, Command};
 use colored::*;
-use error::{Error, Result};
-use git::Git;
-use manifest::GlProjects;
+use glrepo::cancel::{self, CancelToken};
+use glrepo::config::GlConfig;
+use glrepo::git::SyncContext;
+use glrepo::manifest_repo::ManifestRepo;
+use glrepo::process::{self, Timeout};
+use glrepo::report::{JsonLines, Live, Plain, Quiet};
+use glrepo::{export, repo_xml};
+use glrepo::{
+    Error, ForEachOptions, GlProject, Outcome, Outcomes, Result, SyncOptions, SyncStatus, Workspace,
+};
+use output::{Format, Progress};
+use std::io::IsTerminal;
 use std::path::Path;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::mpsc::channel;
-use std::sync::{Arc, Mutex};
-use threadpool::ThreadPool;
-
-/// Errors that comes from a thread in case a command fails to execute.
-struct ThreadError(String, Error);
-
-///
-/// Read YAML Manifest from and return GlProjects structure on success.
-/// # Arguments
-///
-/// - `manifest_file` Path to manifest file.
-///
-/// # Error
-///
-/// Error::Manifest.
-fn load_manifest<P: AsRef<Path>>(p: P) -> Result<GlProjects> {
-    log::info!("Read manifest from: '{}'", p.as_ref().display());
-    let projects = GlProjects::try_from_yaml(&p)?;
-    Ok(projects)
-}
 
 ///
 /// Do the command specified via command line
@@ -41,24 +24,41 @@ fn load_manifest<P: AsRef<Path>>(p: P) -> Result<GlProjects> {
 ///
 /// # Arguments
 /// * `args` - Command line argument data.
-/// * `projects` Projects data structure.
+/// * `workspace` Manifest and configuration.
 ///
 /// # Error
 ///
 /// see GlRepo::error::Error
-fn do_single_command(args: &Args, projects: &mut GlProjects) -> Result<bool> {
+fn do_single_command(args: &Args, workspace: &mut Workspace) -> Result<bool> {
     match &args.command {
         Command::Path { project } => {
-            if let Some(project) = projects.projects.get(project) {
-                println!("{}", &project.path.display());
+            if let Some(project) = workspace.manifest().projects.get(project) {
+                if args.format == Format::Text {
+                    println!("{}", &project.path.display());
+                } else {
+                    output::print(
+                        args.format,
+                        &output::PathEntry {
+                            name: project.name.clone(),
+                            path: project.path.clone(),
+                        },
+                    )?;
+                }
             } else {
                 return Err(Error::ProjectNotFound(project.into()));
             }
             Ok(true)
         }
         Command::List { fetch_url, path } => {
-            for (name, project) in &projects.projects {
-                print!("{}", name);
+            let selected = workspace.select(&[], &args.groups);
+            if args.format != Format::Text {
+                let entries: Vec<output::ProjectEntry> =
+                    selected.iter().map(output::ProjectEntry::from).collect();
+                output::print(args.format, &output::Projects { projects: entries })?;
+                return Ok(true);
+            }
+            for project in &selected {
+                print!("{}", project.name);
                 if *fetch_url {
                     print!(",{}", project.fetch_url);
                 }
@@ -76,160 +76,312 @@ fn do_single_command(args: &Args, projects: &mut GlProjects) -> Result<bool> {
             fetch_url,
             reference,
             auto_sync,
-            timeout_ms,
+            timeout,
+            kill_grace,
         } => {
-            if projects.projects.get(project_name).is_some() {
-                return Err(Error::General(format!(
-                    "Project: '{}' already exists",
-                    project_name
-                )));
-            }
-            let repo = Git::init(path)?;
-            repo.remote("origin", fetch_url)?;
-            projects.insert(
-                project_name,
-                manifest::GlProject {
-                    name: project_name.to_string(),
-                    path: path.clone(),
-                    fetch_url: fetch_url.clone(),
-                    reference: reference.clone(),
-                    auto_sync: *auto_sync,
+            let project = GlProject {
+                name: project_name.to_string(),
+                path: path.clone(),
+                fetch_url: fetch_url.clone(),
+                reference: reference.clone(),
+                auto_sync: true,
+                groups: if *auto_sync {
+                    vec![]
+                } else {
+                    vec![String::from("notdefault")]
+                },
+                ..Default::default()
+            };
+            workspace.create(
+                project,
+                run_command,
+                Timeout {
+                    limit: timeout.map(Into::into),
+                    grace: (*kill_grace).into(),
                 },
-            );
-            projects.save_to_yaml(&args.gl_manifest)?;
-            if let Err(e) = process::spawn_shell_and_wait(
-                project_name,
-                path,
-                run_command.into(),
-                std::time::Duration::from_millis(*timeout_ms),
-            ) {
-                return Err(e);
+            )?;
+            Ok(true)
+        }
+        Command::Export { output } => {
+            let manifest =
+                export::resolved(workspace.manifest(), workspace.select(&[], &args.groups));
+            let s = match args.format {
+                Format::Text | Format::Yaml => export::to_yaml(&manifest)?,
+                Format::Json => export::to_json(&manifest)?,
+                Format::Toml => export::to_toml(&manifest)?,
+                Format::RepoXml => repo_xml::to_xml(&manifest)?,
+                Format::GitSubmodules => {
+                    let ctx = SyncContext {
+                        credentials: workspace.config().credentials.clone(),
+                        cancel: workspace.cancel_token().clone(),
+                        ..Default::default()
+                    };
+                    export::to_gitmodules(&manifest, &ctx)?
+                }
+            };
+            write_output(output.as_deref(), &s)?;
+            Ok(true)
+        }
+        Command::Lock => {
+            let lock_file = workspace.lock(&workspace.select(&[], &args.groups))?;
+            if args.format == Format::Text {
+                println!("{}", lock_file.display());
+            } else {
+                output::print(args.format, &output::LockEntry { lock_file })?;
             }
-
             Ok(true)
         }
         _ => Ok(false),
     }
 }
 
+///
+/// Print the result of every project as a JSON or YAML report.
+/// 'report' returns the sync report of a project that is done.
+fn print_report<T>(
+    args: &Args,
+    outcomes: &Outcomes<T>,
+    report: impl Fn(&T) -> Option<glrepo::SyncReport>,
+) -> Result<()> {
+    let results = outcomes
+        .projects
+        .iter()
+        .map(|p| {
+            let (ok, report, error) = match &p.outcome {
+                Outcome::Done(value) => (true, report(value), None),
+                Outcome::Failed(e) | Outcome::Interrupted(e) => (false, None, Some(e.to_string())),
+                Outcome::NotStarted => (false, None, Some(String::from("not started"))),
+                Outcome::Blocked(dependency) => (
+                    false,
+                    None,
+                    Some(format!("not started, dependency: {} failed", dependency)),
+                ),
+            };
+            output::ProjectResult {
+                project: p.name.clone(),
+                ok,
+                status: report.map(|r| r.status),
+                attempts: report.map(|r| r.attempts),
+                error,
+            }
+        })
+        .collect();
+    output::print(
+        args.format,
+        &output::Report {
+            command: args.command.name().to_string(),
+            results,
+        },
+    )
+}
+
+///
+/// Returns Error::Summary listing the failed, interrupted and not started
+/// projects, or Ok if every project succeeded.
+fn summary<T>(outcomes: &Outcomes<T>) -> Result<()> {
+    let failure = match outcomes.failure() {
+        Some(failure) => failure,
+        None => return Ok(()),
+    };
+    let interrupted = outcomes.names(|o| matches!(o, Outcome::Interrupted(_)));
+    if outcomes.interrupted {
+        let not_started =
+            outcomes.names(|o| matches!(o, Outcome::NotStarted | Outcome::Blocked(_)));
+        let completed = outcomes.projects.len() - interrupted.len() - not_started.len();
+        let mut summary = format!(
+            "Interrupted: {} completed, {} interrupted, {} not started\n",
+            completed,
+            interrupted.len(),
+            not_started.len()
+        );
+        for (title, names) in [("Interrupted", &interrupted), ("Not started", &not_started)] {
+            if !names.is_empty() {
+                summary += &format!("\n{}:\n{}\n", title, names.join("\n"));
+            }
+        }
+        return Err(Error::Summary(failure, summary));
+    }
+
+    let errors: Vec<&str> = outcomes.errors().map(|(name, _)| name).collect();
+    eprintln!();
+    let mut summary = format!(
+        "The following {} has errors:\n\n",
+        if errors.len() == 1 {
+            String::from("project")
+        } else {
+            format!("{} projects", errors.len())
+        }
+    );
+    for name in errors.iter() {
+        summary += &format!("{}\n", name);
+    }
+    let blocked: Vec<String> = outcomes
+        .projects
+        .iter()
+        .filter_map(|p| match &p.outcome {
+            Outcome::Blocked(dependency) => Some(format!("{} (depends on {})", p.name, dependency)),
+            _ => None,
+        })
+        .collect();
+    if !blocked.is_empty() {
+        summary += &format!(
+            "\nNot started due to a failed dependency:\n\n{}\n",
+            blocked.join("\n")
+        );
+    }
+    let not_started = outcomes.names(|o| matches!(o, Outcome::NotStarted));
+    if !not_started.is_empty() {
+        summary += &format!(
+            "\nNot started due to --fail-fast:\n\n{}\n",
+            not_started.join("\n")
+        );
+    }
+    Err(Error::Summary(failure, summary))
+}
+
 ///
 /// Run command on every project in the manifest.
 ///
 /// # Error
 /// return GlRepo::Error on failure.
 ///
-fn do_for_each_command(args: &Args, projects: &GlProjects) -> Result<()> {
-    let (tx, rx) = channel();
-    if projects.projects.is_empty() {
+fn do_for_each_command(args: &Args, workspace: &Workspace) -> Result<()> {
+    if workspace.manifest().projects.is_empty() {
         log::warn!("There is no projects in the manifest");
         return Ok(());
     }
 
-    let pool = ThreadPool::new(args.jobs);
-
-    // Increment by one to make sure we don't terminate
-    // until all threads has been handled by the thread pool.
-    let pending = Arc::new(Mutex::new(AtomicUsize::new(1)));
-    let projects = projects.projects.clone();
-    for (name, project) in projects {
-        match &args.command {
-            Command::Sync { projects } => {
-                let filtered_projects = !projects.is_empty();
-                if !filtered_projects || projects.iter().any(|p| *p == name) {
-                    let tx2 = tx.clone();
-                    let p2 = pending.clone();
-                    p2.lock().unwrap().fetch_add(1, Ordering::Relaxed);
-                    // Add function to the thread pool.
-                    pool.execute(move || {
-                        // Only sync projects that has auto_sync set to true
-                        // or sync if projects was explicit selected.
-                        if project.auto_sync || filtered_projects {
-                            log::info!("Sync: {}", name);
-                            if let Err(e) = Git::sync(&name, &project) {
-                                tx2.send(ThreadError(name.clone(), e)).ok();
-                            }
+    match &args.command {
+        Command::Sync {
+            projects,
+            on_dirty,
+            locked,
+            depth,
+            retries,
+        } => {
+            let options = SyncOptions {
+                on_dirty: *on_dirty,
+                locked: *locked,
+                depth: *depth,
+                retries: *retries,
+            };
+            let outcomes = workspace.sync(workspace.select(projects, &args.groups), &options)?;
+            if args.format != Format::Text {
+                print_report(args, &outcomes, |report| Some(*report))?;
+            } else {
+                let notices: Vec<_> = outcomes
+                    .done()
+                    .filter(|(_, report)| {
+                        report.status != SyncStatus::Synced || report.attempts > 1
+                    })
+                    .collect();
+                if !notices.is_empty() {
+                    eprintln!();
+                    for (name, report) in notices {
+                        if report.attempts > 1 {
+                            eprintln!(
+                                "{}: {} ({} fetch attempts)",
+                                name.bold(),
+                                report.status,
+                                report.attempts
+                            );
+                        } else {
+                            eprintln!("{}: {}", name.bold(), report.status);
                         }
-                        p2.lock().unwrap().fetch_sub(1, Ordering::Relaxed);
-                    });
+                    }
                 }
             }
-            Command::ForEach { args, timeout_ms } => {
-                let timeout_ms = *timeout_ms;
-                let args = args.clone();
-                let tx2 = tx.clone();
-                let p2 = pending.clone();
-                p2.lock().unwrap().fetch_add(1, Ordering::Relaxed);
-                // Add function to the thread pool.
-                pool.execute(move || {
-                    if let Err(e) = process::spawn_shell_and_wait(
-                        &name,
-                        &project.path,
-                        args,
-                        std::time::Duration::from_millis(timeout_ms),
-                    ) {
-                        tx2.send(ThreadError(name.clone(), e)).ok();
-                    }
-                    p2.lock().unwrap().fetch_sub(1, Ordering::Relaxed);
-                });
+            summary(&outcomes)
+        }
+        Command::Unshallow { projects } => {
+            let outcomes = workspace.unshallow(workspace.select(projects, &args.groups));
+            if args.format != Format::Text {
+                print_report(args, &outcomes, |_| None)?;
             }
-            Command::Changed { ls_files } => match Git::open(&project.path) {
-                Ok(repo) => {
-                    let files = repo.changed()?;
-                    if !files.is_empty() {
-                        println!("{}", name);
-
+            summary(&outcomes)
+        }
+        Command::ForEach {
+            args: shell_args,
+            timeout,
+            kill_grace,
+            output,
+            log_dir,
+        } => {
+            let options = ForEachOptions {
+                timeout: timeout.map(Into::into),
+                kill_grace: (*kill_grace).into(),
+                output: process::Output::new(*output, log_dir.as_deref())?,
+                // Keep the report on stdout a valid document.
+                stdout_to_stderr: args.format != Format::Text,
+            };
+            let outcomes =
+                workspace.for_each(workspace.select(&[], &args.groups), shell_args, &options);
+            if args.format != Format::Text {
+                print_report(args, &outcomes, |_| None)?;
+            }
+            summary(&outcomes)
+        }
+        Command::Status => {
+            let outcomes = workspace.status(workspace.select(&[], &args.groups));
+            let entries: Vec<output::StatusEntry> = outcomes
+                .projects
+                .iter()
+                .filter_map(|p| match &p.outcome {
+                    Outcome::Done(status) => Some(output::StatusEntry {
+                        name: p.name.clone(),
+                        status: Some(status.clone()),
+                        error: None,
+                    }),
+                    Outcome::Failed(e) | Outcome::Interrupted(e) => Some(output::StatusEntry {
+                        name: p.name.clone(),
+                        status: None,
+                        error: Some(e.to_string()),
+                    }),
+                    Outcome::NotStarted | Outcome::Blocked(_) => None,
+                })
+                .collect();
+            if args.format == Format::Text {
+                print!("{}", output::status_table(&entries));
+            } else {
+                output::print(args.format, &output::Projects { projects: entries })?;
+            }
+            summary(&outcomes)
+        }
+        Command::Changed { ls_files } => {
+            let outcomes = workspace.changed(workspace.select(&[], &args.groups));
+            let mut changed = vec![];
+            for p in &outcomes.projects {
+                match &p.outcome {
+                    Outcome::Done(files) if files.is_empty() => {}
+                    Outcome::Done(files) if args.format != Format::Text => {
+                        changed.push(output::ChangedEntry::new(&p.name, files));
+                    }
+                    Outcome::Done(files) => {
+                        println!("{}", p.name);
                         if *ls_files {
                             println!("{}", files);
                         }
                     }
+                    Outcome::Failed(e) | Outcome::Interrupted(e) if args.format != Format::Text => {
+                        changed.push(output::ChangedEntry {
+                            name: p.name.clone(),
+                            files: vec![],
+                            error: Some(e.to_string()),
+                        });
+                    }
+                    Outcome::Failed(e) | Outcome::Interrupted(e) => {
+                        log::error!("{} Make sure sync has been run", e);
+                    }
+                    Outcome::NotStarted | Outcome::Blocked(_) => {}
                 }
-                Err(e) => {
-                    log::error!("{} Make sure sync has been run", e);
-                }
-            },
-            _ => panic!("Command: {:#?} not implemented", args.command),
-        }
-    }
-    // All projects are now pushed decrement by one
-    // and then wait until all Projects has done it's job.
-    pending.lock().unwrap().fetch_sub(1, Ordering::Relaxed);
-    let mut errors = vec![];
-    while *pending.lock().unwrap().get_mut() > 0 {
-        match rx.try_recv() {
-            Ok(e) => {
-                log::error!("Project: {}: {}", e.0, e.1);
-                errors.push(e);
             }
-            Err(_) => {
-                // timeout or channel endpoint terminated
-                std::thread::sleep(std::time::Duration::from_millis(100));
+            if args.format != Format::Text {
+                output::print(args.format, &output::Projects { projects: changed })?;
             }
+            summary(&outcomes)
         }
+        _ => panic!("Command: {:#?} not implemented", args.command),
     }
-
-    // We need to empty any errors coming from threads.
-    // If there are error messages left.
-    while let Ok(e) = rx.try_recv() {
-        log::error!("Project: {}: {}", e.0.bold(), e.1);
-        errors.push(e);
-    }
-
-    if !errors.is_empty() {
-        eprintln!();
-        let mut summary = format!(
-            "The following {} has errors:\n\n",
-            if errors.len() == 1 {
-                String::from("project")
-            } else {
-                format!("{} projects", errors.len())
-            }
-        );
-        for e in errors.iter() {
-            summary += &format!("{}\n", e.0);
-        }
-        return Err(Error::Summary(summary));
-    }
-    Ok(())
 }
 
 ///
@@ -241,20 +393,161 @@ fn do_for_each_command(args: &Args, projects: &GlProjects) -> Result<()> {
 /// # Error
 /// return Error on failure.
 ///
-fn run_main() -> Result<()> {
-    let args = Args::init()?;
-    let mut projects = load_manifest(&args.gl_manifest)?;
-    if do_single_command(&args, &mut projects)? {
+fn run_main(args: &Args) -> Result<()> {
+    let cancel = CancelToken::new();
+    cancel::install(cancel.clone())?;
+    if let Command::Sync {
+        on_dirty, retries, ..
+    } = &args.command
+    {
+        // The manifest repository is updated first so projects are synced with the latest manifest,
+        // only if the manifest in use is in it.
+        if let Some(repo) = ManifestRepo::load(&args.gl_config_home)?
+            .filter(|_| ManifestRepo::contains(&args.gl_config_home, &args.gl_manifest))
+        {
+            let config = GlConfig::try_from_yaml(&args.gl_config_home.join("config.yaml"))?;
+            let ctx = SyncContext {
+                credentials: config.credentials,
+                cancel: cancel.clone(),
+                ..Default::default()
+            };
+            let retries = retries.unwrap_or(ManifestRepo::FETCH_RETRIES);
+            let report = repo.sync(&args.gl_config_home, *on_dirty, retries, &ctx)?;
+            if report.status == SyncStatus::SkippedDirty {
+                log::warn!(
+                    "Manifest repository: '{}' {}",
+                    ManifestRepo::dir(&args.gl_config_home).display(),
+                    report.status
+                );
+            }
+        }
+    }
+    let mut workspace = Workspace::open(&args.gl_manifest, &args.gl_config_home)?;
+    workspace.set_cancel_token(cancel);
+    workspace.jobs = args.jobs;
+    workspace.fail_fast = args.fail_fast;
+    workspace.sort = args.sort;
+    // Redrawing progress would garble the output of for-each and status.
+    if let Command::Sync { .. } | Command::Unshallow { .. } = &args.command {
+        match args.progress {
+            Progress::Live if std::io::stderr().is_terminal() => {
+                workspace.set_reporter(Live::new())
+            }
+            Progress::Live => workspace.set_reporter(Plain),
+            Progress::Quiet => workspace.set_reporter(Quiet),
+            Progress::Json => workspace.set_reporter(JsonLines),
+        }
+    }
+    if do_single_command(args, &mut workspace)? {
         return Ok(());
     }
 
-    do_for_each_command(&args, &projects)
+    do_for_each_command(args, &workspace)
+}
+
+///
+/// Clone the manifest repository and make its manifest the default.
+///
+/// # Error
+/// return Error on failure.
+fn do_init(args: &Args) -> Result<()> {
+    if let Command::Init {
+        manifest_url,
+        manifest_branch,
+        manifest_name,
+    } = &args.command
+    {
+        let config = GlConfig::try_from_yaml(&args.gl_config_home.join("config.yaml"))?;
+        let ctx = SyncContext {
+            credentials: config.credentials,
+            ..Default::default()
+        };
+        cancel::install(ctx.cancel.clone())?;
+        std::fs::create_dir_all(&args.gl_config_home).map_err(|e| {
+            Error::General(format!(
+                "Could not create: '{}' cause: {}",
+                args.gl_config_home.display(),
+                e
+            ))
+        })?;
+        let repo = ManifestRepo::init(
+            &args.gl_config_home,
+            manifest_url,
+            manifest_branch,
+            manifest_name,
+            &ctx,
+        )?;
+        let manifest = repo.manifest_file(&args.gl_config_home);
+        if args.format == Format::Text {
+            println!("{}", manifest.display());
+        } else {
+            output::print(args.format, &output::ManifestEntry { manifest, repo })?;
+        }
+    }
+    Ok(())
+}
+
+///
+/// Convert a google repo XML manifest to YAML.
+/// Includes are kept as is since XML manifests can be included directly.
+///
+/// # Error
+/// Error::Manifest if the XML manifest can not be read.
+fn do_import(xml: &Path, yaml_file: Option<&Path>) -> Result<()> {
+    let s = std::fs::read_to_string(xml).map_err(|e| {
+        Error::Manifest(format!(
+            "Could not load manifest file: '{}' cause: {}",
+            xml.display(),
+            e
+        ))
+    })?;
+    let yaml = serde_yaml::to_string(&repo_xml::to_mapping(
+        xml,
+        &s,
+        repo_xml::manifest_url(xml).as_deref(),
+    )?)
+    .map_err(|e| Error::General(e.to_string()))?;
+    write_output(yaml_file, &yaml)
+}
+
+///
+/// Write 's' to 'file' or to stdout if no file is given.
+///
+/// # Error
+/// Error::General if the file could not be written.
+fn write_output(file: Option<&Path>, s: &str) -> Result<()> {
+    match file {
+        Some(file) => std::fs::write(file, s)
+            .map_err(|e| Error::General(format!("output to: '{}' cause: '{}'", file.display(), e))),
+        None => {
+            print!("{}", s);
+            Ok(())
+        }
+    }
 }
 
 fn main() {
-    if let Err(e) = run_main() {
+    let mut args = Args::init();
+    // The manifest does not exist before init.
+    let res = match &args.command {
+        Command::Init { .. } => do_init(&args),
+        Command::ImportRepoManifest { xml, output } => do_import(xml, output.as_deref()),
+        _ => args.expand_manifest().and_then(|_| run_main(&args)),
+    };
+    if let Err(e) = res {
         log::error!("{}", e);
-        std::process::exit(-1);
+        // Project errors are already part of the command report.
+        if matches!(args.format, Format::Json | Format::Yaml) && !matches!(e, Error::Summary(_, _))
+        {
+            output::print(
+                args.format,
+                &output::ErrorReport {
+                    error: e.to_string(),
+                },
+            )
+            .ok();
+        }
+        std::process::exit(e.exit_code());
     }
     log::info!("Success");
 }
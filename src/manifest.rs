@@ -1,58 +1,583 @@
 use crate::error::Error;
+use crate::repo_xml;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
+use serde_yaml::{Mapping, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
 use std::{fmt, fs};
 #[derive(Serialize, Deserialize, Default, Debug, Clone)]
 pub struct GlProject {
     #[serde(skip)]
     pub name: String,
+    /// Position in the manifest, projects in included manifests first.
+    /// Only set when the manifest is loaded by GlProjects::try_from_yaml, it is 0 for
+    /// projects deserialized or built directly and Sort::Manifest then orders them by name.
+    #[serde(skip)]
+    pub index: usize,
+    /// If not set it is resolved from remote and repo_name.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub fetch_url: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub push_url: String,
+    /// Name of a remote in the manifest remotes, if not set default_remote is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote: Option<String>,
+    /// Repository name appended to the remote URL, if not set the project name is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repo_name: Option<String>,
+    /// Additional manifest remotes configured in the local repository.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_remotes: Vec<String>,
+    /// Git remotes other than origin, resolved from extra_remotes.
+    #[serde(skip)]
+    pub git_remotes: Vec<GitRemote>,
     #[serde(default = "PathBuf::default")]
     pub path: PathBuf,
     #[serde(default = "String::default")]
     pub reference: String,
+    /// Deprecated, false is migrated to the group 'notdefault' when the manifest is verified.
     #[serde(default = "default_true")]
     pub auto_sync: bool,
+    /// How local commits are combined with upstream on sync.
+    /// If not set the manifest sync_strategy is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sync_strategy: Option<SyncStrategy>,
+    /// Groups the project belongs to, used to select projects.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    /// Shallow clone with history truncated to 'depth' commits.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub depth: Option<u32>,
+    /// Partial clone filter.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<CloneFilter>,
+    /// Number of times a fetch failing with a network error is retried.
+    /// If not set the manifest fetch_retries is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fetch_retries: Option<u32>,
+    /// Cone mode sparse checkout directories, if empty everything is checked out.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sparse: Vec<String>,
+    /// Max time for commands run by for-each, example: 10m. Overridden by the command line timeout.
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub timeout: Option<Duration>,
+    /// Environment variables set for commands run by for-each.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// Files copied from the project to the projects_dir after sync.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub copy_files: Vec<FileMapping>,
+    /// Symbolic links in the projects_dir to files in the project, created after sync.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub link_files: Vec<FileMapping>,
+    /// Projects sync and for-each finish before this project is started.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<String>,
+}
+
+/// A file in a project, 'src', and where it is installed in the projects_dir, 'dest'.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct FileMapping {
+    pub src: PathBuf,
+    pub dest: PathBuf,
+}
+
+impl FileMapping {
+    ///
+    /// Verify that 'src' stays inside the project and 'dest' inside the projects_dir,
+    /// both must be relative paths without '..'.
+    /// Error
+    /// A message naming the rejected path.
+    pub fn check(&self) -> Result<(), String> {
+        for (field, path) in [("src", &self.src), ("dest", &self.dest)] {
+            let inside = path.file_name().is_some()
+                && path
+                    .components()
+                    .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+            if !inside {
+                return Err(format!(
+                    "{}: '{}' must be a relative path without '..'",
+                    field,
+                    path.display()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Partial clone filters.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloneFilter {
+    /// Omit all blobs, they are fetched on demand.
+    #[serde(rename = "blob:none")]
+    BlobNone,
+    /// Omit all trees and blobs, they are fetched on demand.
+    #[serde(rename = "tree:0")]
+    TreeZero,
+}
+
+impl fmt::Display for CloneFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CloneFilter::BlobNone => write!(f, "blob:none"),
+            CloneFilter::TreeZero => write!(f, "tree:0"),
+        }
+    }
+}
+
+impl GlProject {
+    ///
+    /// Returns true if the project is member of 'group'.
+    /// Every project is member of the groups 'all' and its own name.
+    /// Projects not in the group 'notdefault' are members of 'default'.
+    pub fn in_group(&self, group: &str) -> bool {
+        match group {
+            "all" => true,
+            "default" => !self.in_group("notdefault"),
+            "notdefault" => self.groups.iter().any(|g| g == group),
+            _ => self.name == group || self.groups.iter().any(|g| g == group),
+        }
+    }
+}
+
+/// Order projects are listed and run in.
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Sort {
+    /// The order of the manifest, projects in included manifests first.
+    #[default]
+    Manifest,
+    Name,
+    Path,
+}
+
+impl Sort {
+    /// Sort 'projects', Manifest uses GlProject::index and the name for equal indexes.
+    pub fn sort(self, projects: &mut [GlProject]) {
+        match self {
+            Sort::Manifest => {
+                projects.sort_by(|a, b| a.index.cmp(&b.index).then(a.name.cmp(&b.name)))
+            }
+            Sort::Name => projects.sort_by(|a, b| a.name.cmp(&b.name)),
+            Sort::Path => projects.sort_by(|a, b| a.path.cmp(&b.path).then(a.name.cmp(&b.name))),
+        }
+    }
+}
+
+fn default_groups() -> Vec<String> {
+    vec![String::from("default")]
+}
+
+/// Select projects from group expressions.
+/// A group prefixed with '!' excludes the projects in that group.
+#[derive(Debug)]
+pub struct GroupFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl GroupFilter {
+    ///
+    /// Create a filter from command line groups.
+    /// If no group to include is given 'default_groups' are included.
+    pub fn new(groups: &[String], default_groups: &[String]) -> Self {
+        let mut include = vec![];
+        let mut exclude = vec![];
+        for group in groups.iter().map(|g| g.trim()).filter(|g| !g.is_empty()) {
+            match group.strip_prefix('!') {
+                Some(g) => exclude.push(g.to_string()),
+                None => include.push(group.to_string()),
+            }
+        }
+        if include.is_empty() {
+            include = default_groups.to_vec();
+        }
+        Self { include, exclude }
+    }
+
+    pub fn matches(&self, project: &GlProject) -> bool {
+        self.include.iter().any(|g| project.in_group(g))
+            && !self.exclude.iter().any(|g| project.in_group(g))
+    }
+}
+
+/// A named remote in the manifest.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct GlRemote {
+    /// Base URL, the repo_name is appended to it.
+    /// If it contains {repo_name} that is replaced with the repo_name instead.
+    pub fetch: String,
+    /// Base URL for push.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub push: Option<String>,
+}
+
+impl GlRemote {
+    fn expand(base: &str, repo_name: &str) -> String {
+        if base.contains("{repo_name}") {
+            base.replace("{repo_name}", repo_name)
+        } else {
+            format!("{}/{}", base.trim_end_matches('/'), repo_name)
+        }
+    }
+
+    pub fn fetch_url(&self, repo_name: &str) -> String {
+        Self::expand(&self.fetch, repo_name)
+    }
+
+    pub fn push_url(&self, repo_name: &str) -> Option<String> {
+        self.push.as_ref().map(|p| Self::expand(p, repo_name))
+    }
+}
+
+/// A git remote to configure in a local repository.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GitRemote {
+    pub name: String,
+    pub fetch_url: String,
+    pub push_url: Option<String>,
 }
 
 fn default_true() -> bool {
     true
 }
 
+/// Strategy used by sync when the local branch and upstream has diverged.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyncStrategy {
+    /// Only fast forward, fail if local branch has diverged.
+    #[default]
+    FfOnly,
+    /// Rebase local commits on top of upstream.
+    Rebase,
+    /// Create a merge commit.
+    Merge,
+    /// Throw away local commits and reset to upstream.
+    ResetHard,
+}
+
+impl fmt::Display for SyncStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            SyncStrategy::FfOnly => "ff-only",
+            SyncStrategy::Rebase => "rebase",
+            SyncStrategy::Merge => "merge",
+            SyncStrategy::ResetHard => "reset-hard",
+        };
+        write!(f, "{}", s)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct GlProjects {
     #[serde(default = "PathBuf::default")]
     pub projects_dir: PathBuf,
     #[serde(default = "String::default")]
     pub default_reference: String,
+    /// Default sync strategy for projects that does not specify one.
+    #[serde(default)]
+    pub sync_strategy: SyncStrategy,
+    /// Default number of retries for fetches failing with a network error.
+    #[serde(default)]
+    pub fetch_retries: u32,
+    /// Groups selected when no group is given on the command line.
+    #[serde(default = "default_groups")]
+    pub default_groups: Vec<String>,
+    /// Named remotes projects can fetch from.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub remotes: HashMap<String, GlRemote>,
+    /// Remote used by projects without fetch_url or remote.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub default_remote: String,
+    /// Manifests to include, relative to the directory of this manifest.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<PathBuf>,
+    /// Projects to remove from included manifests.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remove: Vec<String>,
+    #[serde(default)]
     pub projects: HashMap<String, GlProject>,
 }
 
+/// Returns true if 'path' is a google repo XML manifest.
+fn is_xml(path: &Path) -> bool {
+    path.extension().map(|e| e == "xml").unwrap_or(false)
+}
+
+/// Show a list of files as 'a', 'b'
+fn display_files(files: &[PathBuf]) -> String {
+    files
+        .iter()
+        .map(|f| format!("'{}'", f.display()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Reads a manifest file and all of its includes into one YAML mapping.
+#[derive(Default)]
+struct ManifestLoader {
+    /// Files currently being loaded, used to detect include cycles.
+    stack: Vec<PathBuf>,
+    /// Files that has defined or changed each project.
+    sources: HashMap<String, Vec<PathBuf>>,
+}
+
+impl ManifestLoader {
+    /// Read a YAML manifest or a google repo XML manifest.
+    fn read(path: &Path) -> Result<Mapping, Error> {
+        let s = fs::read_to_string(path).map_err(|e| {
+            Error::Manifest(format!(
+                "Could not load manifest file: '{}' cause: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        if is_xml(path) {
+            return repo_xml::to_mapping(path, &s, repo_xml::manifest_url(path).as_deref());
+        }
+        match serde_yaml::from_str::<Value>(&s)
+            .map_err(|e| Error::Manifest(format!("'{}': {}", path.display(), e)))?
+        {
+            Value::Mapping(m) => Ok(m),
+            Value::Null => Ok(Mapping::new()),
+            _ => Err(Error::Manifest(format!(
+                "'{}': Expected a YAML mapping",
+                path.display()
+            ))),
+        }
+    }
+
+    /// Returns the list in 'key' removed from the mapping.
+    fn take_list(path: &Path, m: &mut Mapping, key: &str) -> Result<Vec<String>, Error> {
+        match m.remove(&Value::from(key)) {
+            None | Some(Value::Null) => Ok(vec![]),
+            Some(v) => serde_yaml::from_value::<Vec<String>>(v).map_err(|e| {
+                Error::Manifest(format!("'{}': field {}: {}", path.display(), key, e))
+            }),
+        }
+    }
+
+    /// Returns the projects mapping removed from the manifest mapping.
+    fn take_projects(path: &Path, m: &mut Mapping) -> Result<Mapping, Error> {
+        match m.remove(&Value::from("projects")) {
+            None | Some(Value::Null) => Ok(Mapping::new()),
+            Some(Value::Mapping(p)) => Ok(p),
+            Some(_) => Err(Error::Manifest(format!(
+                "'{}': field projects must be a mapping",
+                path.display()
+            ))),
+        }
+    }
+
+    /// Insert a top level field, mappings like remotes are merged by key.
+    fn merge_field(merged: &mut Mapping, k: Value, v: Value) {
+        match (merged.get_mut(&k), v) {
+            (Some(Value::Mapping(current)), Value::Mapping(fields)) => {
+                for (field, value) in fields {
+                    current.insert(field, value);
+                }
+            }
+            (_, v) => {
+                merged.insert(k, v);
+            }
+        }
+    }
+
+    /// Remove the projects in 'remove', the rest are kept in order.
+    fn remove_projects(&mut self, path: &Path, projects: &mut Mapping, remove: Vec<String>) {
+        for name in &remove {
+            if !projects.contains_key(&Value::from(name.as_str())) {
+                log::warn!("'{}': remove: Project '{}' not found", path.display(), name);
+            }
+            self.sources.remove(name);
+        }
+        // Mapping::remove moves the last project to the removed position.
+        *projects = std::mem::replace(projects, Mapping::new())
+            .into_iter()
+            .filter(|(k, _)| !remove.iter().any(|name| k.as_str() == Some(name)))
+            .collect();
+    }
+
+    ///
+    /// Load a manifest and the manifests it includes.
+    /// A project may only be defined in one of the files.
+    fn load(&mut self, path: &Path) -> Result<Mapping, Error> {
+        let path = path.canonicalize().map_err(|e| {
+            Error::Manifest(format!(
+                "Could not load manifest file: '{}' cause: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        if self.stack.contains(&path) {
+            let mut cycle = self.stack.clone();
+            cycle.push(path);
+            return Err(Error::Manifest(format!(
+                "Include cycle: {}",
+                cycle
+                    .iter()
+                    .map(|f| format!("'{}'", f.display()))
+                    .collect::<Vec<_>>()
+                    .join(" -> ")
+            )));
+        }
+        self.stack.push(path.clone());
+
+        let mut own = Self::read(&path)?;
+        let includes = Self::take_list(&path, &mut own, "include")?;
+        let remove = Self::take_list(&path, &mut own, "remove")?;
+        let own_projects = Self::take_projects(&path, &mut own)?;
+        let dir = path
+            .parent()
+            .unwrap_or_else(|| Path::new("/"))
+            .to_path_buf();
+
+        let mut merged = Mapping::new();
+        let mut projects = Mapping::new();
+        for include in includes {
+            let mut included = self.load(&dir.join(include))?;
+            let included_projects = Self::take_projects(&path, &mut included)?;
+            for (k, v) in included {
+                Self::merge_field(&mut merged, k, v);
+            }
+            for (k, v) in included_projects {
+                projects.insert(k, v);
+            }
+        }
+
+        for (k, v) in own_projects {
+            let name = k.as_str().unwrap_or_default().to_string();
+            let files = self.sources.entry(name.clone()).or_default();
+            if let Some(first) = files.first() {
+                if *first != path {
+                    return Err(Error::Manifest(format!(
+                        "Project '{}' is defined in both '{}' and '{}'",
+                        name,
+                        first.display(),
+                        path.display()
+                    )));
+                }
+            } else {
+                files.push(path.clone());
+            }
+            projects.insert(k, v);
+        }
+        self.remove_projects(&path, &mut projects, remove);
+
+        // Fields in this manifest has precedence over included manifests.
+        for (k, v) in own {
+            Self::merge_field(&mut merged, k, v);
+        }
+        merged.insert(Value::from("projects"), Value::Mapping(projects));
+        self.stack.pop();
+        Ok(merged)
+    }
+
+    ///
+    /// Apply a local overlay manifest.
+    /// The overlay may add projects, override fields of existing projects and remove projects.
+    fn overlay(&mut self, base: &mut Mapping, path: &Path) -> Result<(), Error> {
+        let mut overlay = Self::read(path)?;
+        let remove = Self::take_list(path, &mut overlay, "remove")?;
+        if !Self::take_list(path, &mut overlay, "include")?.is_empty() {
+            return Err(Error::Manifest(format!(
+                "'{}': include is not supported in local manifests",
+                path.display()
+            )));
+        }
+        let overlay_projects = Self::take_projects(path, &mut overlay)?;
+        let mut projects = Self::take_projects(path, base)?;
+        for (k, v) in overlay_projects {
+            let name = k.as_str().unwrap_or_default().to_string();
+            self.sources
+                .entry(name.clone())
+                .or_default()
+                .push(path.to_path_buf());
+            Self::merge_field(&mut projects, k, v);
+        }
+        self.remove_projects(path, &mut projects, remove);
+        for (k, v) in overlay {
+            Self::merge_field(base, k, v);
+        }
+        base.insert(Value::from("projects"), Value::Mapping(projects));
+        Ok(())
+    }
+
+    /// Convert the merged mapping to GlProjects.
+    /// Errors names the files that defined the failing project.
+    fn finish(self, mut merged: Mapping, manifest: &Path) -> Result<GlProjects, Error> {
+        let projects = Self::take_projects(manifest, &mut merged)?;
+        let mut gl_projects = serde_yaml::from_value::<GlProjects>(Value::Mapping(merged))
+            .map_err(|e| Error::Manifest(format!("'{}': {}", manifest.display(), e)))?;
+        for (index, (k, v)) in projects.into_iter().enumerate() {
+            let name = k.as_str().unwrap_or_default().to_string();
+            let mut project = serde_yaml::from_value::<GlProject>(v).map_err(|e| {
+                let files = self.sources.get(&name).cloned().unwrap_or_default();
+                Error::Manifest(format!(
+                    "Project '{}' defined in {}: {}",
+                    name,
+                    display_files(&files),
+                    e
+                ))
+            })?;
+            project.index = index;
+            gl_projects.projects.insert(name, project);
+        }
+        Ok(gl_projects)
+    }
+}
+
 impl GlProjects {
-    /// Returns a GlProjects data structure from a specified repo manifest in GlProject YAML
-    /// format.
+    /// Returns a GlProjects data structure from a manifest, the manifests it includes
+    /// and the local overlay manifests (*.yaml or *.xml) found in 'local_manifests'.
+    /// Manifests ending with .xml are read as google repo XML manifests.
+    /// Overlays are applied in file name order.
     /// Error
-    /// std::io::Error
+    /// Error::Manifest
     /// # Arguments
     ///
     /// * `manifest_file` full path to YAML manifest.
+    /// * `local_manifests` directory with overlay manifests, ignored if it does not exist.
     ///
-    pub fn try_from_yaml<P: AsRef<Path>>(manifest_file: &P) -> Result<Self, Error> {
-        let s = fs::read_to_string(manifest_file)
-            .map_err(|e| Error::Manifest(format!("Could not load manifest file cause: {}", e)))?;
-        Self::verify(
-            serde_yaml::from_str::<GlProjects>(&s).map_err(|e| Error::General(format!("{}", e)))?,
-        )
+    pub fn try_from_yaml<P: AsRef<Path>, L: AsRef<Path>>(
+        manifest_file: &P,
+        local_manifests: Option<L>,
+    ) -> Result<Self, Error> {
+        let manifest_file = manifest_file.as_ref();
+        let mut loader = ManifestLoader::default();
+        let mut merged = loader.load(manifest_file)?;
+        if let Some(dir) = local_manifests.as_ref().map(|d| d.as_ref()) {
+            if dir.is_dir() {
+                let mut overlays = fs::read_dir(dir)
+                    .map_err(|e| Error::Manifest(format!("'{}' cause: {}", dir.display(), e)))?
+                    .filter_map(|entry| entry.ok().map(|e| e.path()))
+                    .filter(|p| p.extension().map(|e| e == "yaml").unwrap_or(false) || is_xml(p))
+                    .collect::<Vec<_>>();
+                overlays.sort();
+                for overlay in overlays {
+                    log::info!("Apply local manifest: '{}'", overlay.display());
+                    loader.overlay(&mut merged, &overlay)?;
+                }
+            }
+        }
+        Self::verify(loader.finish(merged, manifest_file)?)
     }
 
     fn verify(mut self) -> Result<Self, Error> {
         if self.projects_dir != PathBuf::default() && self.projects_dir.canonicalize().is_err() {
-            return Err(Error::General(
+            return Err(Error::Manifest(
                 "The projects_dir must point to an existing directory!".to_string(),
             ));
         }
         for (name, project) in self.projects.iter_mut() {
+            project.name = name.clone();
             let mut full_path = self.projects_dir.clone();
             // No path set for project
             project.path = if project.path.has_root() {
@@ -69,33 +594,318 @@ impl GlProjects {
             if project.reference.is_empty() {
                 project.reference = self.default_reference.clone();
                 if project.reference.is_empty() {
-                    return Err(Error::General(format!("Project: {} are missing reference and the manifest file does not have the field: default_reference!", name)));
+                    return Err(Error::Manifest(format!("Project: {} are missing reference and the manifest file does not have the field: default_reference!", name)));
+                }
+            }
+
+            if project.fetch_retries.is_none() {
+                project.fetch_retries = Some(self.fetch_retries);
+            }
+
+            if !project.auto_sync {
+                log::warn!(
+                    "Project: {} auto_sync: false is deprecated, use groups: [notdefault]. \
+                     The project is only selected with -g notdefault or -g all",
+                    name
+                );
+                if !project.in_group("notdefault") {
+                    project.groups.push(String::from("notdefault"));
+                }
+                project.auto_sync = true;
+            }
+
+            if project.sync_strategy.is_none() {
+                project.sync_strategy = Some(self.sync_strategy);
+            }
+
+            for (field, files) in [
+                ("copy_files", &project.copy_files),
+                ("link_files", &project.link_files),
+            ] {
+                for file in files {
+                    file.check().map_err(|e| {
+                        Error::Manifest(format!("Project: {} {} {}", name, field, e))
+                    })?;
                 }
             }
+
+            let repo_name = project.repo_name.clone().unwrap_or_else(|| name.clone());
+            let find_remote = |remote: &str| {
+                self.remotes.get(remote).ok_or_else(|| {
+                    Error::Manifest(format!(
+                        "Project: {} uses remote: '{}' which is not in remotes",
+                        name, remote
+                    ))
+                })
+            };
+            if project.fetch_url.is_empty() {
+                let remote = project
+                    .remote
+                    .clone()
+                    .unwrap_or_else(|| self.default_remote.clone());
+                if remote.is_empty() {
+                    return Err(Error::Manifest(format!("Project: {} are missing fetch_url and remote and the manifest file does not have the field: default_remote!", name)));
+                }
+                let remote = find_remote(&remote)?;
+                project.fetch_url = remote.fetch_url(&repo_name);
+                if project.push_url.is_empty() {
+                    project.push_url = remote.push_url(&repo_name).unwrap_or_default();
+                }
+            }
+
+            project.git_remotes.clear();
+            for remote_name in &project.extra_remotes {
+                let remote = find_remote(remote_name)?;
+                project.git_remotes.push(GitRemote {
+                    name: remote_name.clone(),
+                    fetch_url: remote.fetch_url(&repo_name),
+                    push_url: remote.push_url(&repo_name),
+                });
+            }
         }
+        self.verify_dependencies()?;
         Ok(self)
     }
 
+    ///
+    /// Every project in depends_on must be in the manifest and
+    /// a project may not depend on itself, directly or through other projects.
+    /// Error
+    /// Error::Manifest
+    fn verify_dependencies(&self) -> Result<(), Error> {
+        let mut names: Vec<&String> = self.projects.keys().collect();
+        names.sort();
+        // Projects already verified to not be part of a cycle.
+        let mut verified: Vec<&str> = vec![];
+        for name in names {
+            let mut path = vec![name.as_str()];
+            self.visit_dependencies(&mut path, &mut verified)?;
+        }
+        Ok(())
+    }
+
+    /// Depth first search from the last project in 'path'.
+    fn visit_dependencies<'a>(
+        &'a self,
+        path: &mut Vec<&'a str>,
+        verified: &mut Vec<&'a str>,
+    ) -> Result<(), Error> {
+        let name = path[path.len() - 1];
+        if verified.contains(&name) {
+            return Ok(());
+        }
+        for dependency in &self.projects[name].depends_on {
+            if !self.projects.contains_key(dependency) {
+                return Err(Error::Manifest(format!(
+                    "Project: {} depends on: '{}' which is not in the manifest",
+                    name, dependency
+                )));
+            }
+            if let Some(start) = path.iter().position(|n| n == dependency) {
+                let mut cycle = path[start..].to_vec();
+                cycle.push(dependency);
+                return Err(Error::Manifest(format!(
+                    "Dependency cycle: {}",
+                    cycle.join(" -> ")
+                )));
+            }
+            path.push(dependency);
+            self.visit_dependencies(path, verified)?;
+            path.pop();
+        }
+        verified.push(name);
+        Ok(())
+    }
+
+    /// Returns the projects selected by the group expressions in 'groups'.
+    pub fn select(&self, groups: &[String]) -> HashMap<String, GlProject> {
+        let filter = GroupFilter::new(groups, &self.default_groups);
+        self.projects
+            .iter()
+            .filter(|(_, project)| filter.matches(project))
+            .map(|(name, project)| (name.clone(), project.clone()))
+            .collect()
+    }
+
     pub fn insert(&mut self, name: &str, project: GlProject) {
         self.projects.insert(name.into(), project);
     }
 
-    /// Save to a YAML file if file already exists it will be overwritten.
-    /// Error
-    /// std::io::Error
-    /// # Arguments
-    ///
-    /// * `manifest_file` full path to YAML manifest.
     ///
-    pub fn save_to_yaml<P: AsRef<Path>>(&self, manifest: &P) -> Result<(), Error> {
-        fs::write(manifest, serde_yaml::to_string(self).unwrap()).map_err(|e| {
-            Error::Manifest(format!(
-                "output to: '{}' cause: '{}'",
-                manifest.as_ref().display(),
-                e
-            ))
+    /// Returns 'project' as it is written to a manifest, fields that are
+    /// resolved from the manifest defaults are left out and the path is
+    /// relative to the projects_dir.
+    pub fn manifest_entry(&self, project: &GlProject) -> GlProject {
+        let mut entry = project.clone();
+        entry.path = match project.path.strip_prefix(&self.projects_dir) {
+            Ok(path) if path == Path::new(&project.name) => PathBuf::new(),
+            Ok(path) => path.to_path_buf(),
+            Err(_) => project.path.clone(),
+        };
+        if entry.reference == self.default_reference {
+            entry.reference.clear();
+        }
+        if entry.fetch_retries == Some(self.fetch_retries) {
+            entry.fetch_retries = None;
+        }
+        if entry.sync_strategy == Some(self.sync_strategy) {
+            entry.sync_strategy = None;
+        }
+        entry
+    }
+}
+
+///
+/// Add 'project' to the projects of 'manifest_file'.
+/// The rest of the file is left as it is, comments and order included.
+/// Error
+/// Error::Manifest if the file could not be read, written or is an XML manifest.
+/// Error::General if the project already exists in the file.
+pub fn add_project(manifest_file: &Path, project: &GlProject) -> Result<(), Error> {
+    if is_xml(manifest_file) {
+        return Err(Error::Manifest(format!(
+            "'{}': adding projects to XML manifests is not supported",
+            manifest_file.display()
+        )));
+    }
+    let text = fs::read_to_string(manifest_file).map_err(|e| {
+        Error::Manifest(format!(
+            "Could not load manifest file: '{}' cause: {}",
+            manifest_file.display(),
+            e
+        ))
+    })?;
+    let text = insert_project(&text, project)
+        .map_err(|e| Error::Manifest(format!("'{}': {}", manifest_file.display(), e)))?;
+    fs::write(manifest_file, text).map_err(|e| {
+        Error::Manifest(format!(
+            "output to: '{}' cause: '{}'",
+            manifest_file.display(),
+            e
+        ))
+    })
+}
+
+/// Number of spaces a line is indented with.
+fn indent_of(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+/// Returns true if 'line' has no YAML content.
+fn is_blank_or_comment(line: &str) -> bool {
+    let line = line.trim();
+    line.is_empty() || line.starts_with('#')
+}
+
+///
+/// Serialize 'project' as a YAML mapping with only the fields that differs
+/// from the defaults, every line indented with 'indent' spaces per level.
+fn project_to_yaml(project: &GlProject, indent: usize) -> Result<String, Error> {
+    let to_value = |project: &GlProject| {
+        serde_yaml::to_value(project).map_err(|e| Error::General(e.to_string()))
+    };
+    let default: GlProject =
+        serde_yaml::from_str("{}").map_err(|e| Error::General(e.to_string()))?;
+    let (Value::Mapping(fields), Value::Mapping(defaults)) =
+        (to_value(project)?, to_value(&default)?)
+    else {
+        return Err(Error::General(String::from("project is not a mapping")));
+    };
+    let fields: Mapping = fields
+        .into_iter()
+        .filter(|(key, value)| defaults.get(key) != Some(value))
+        .collect();
+    let mut entry = Mapping::new();
+    entry.insert(Value::String(project.name.clone()), Value::Mapping(fields));
+    let yaml = serde_yaml::to_string(&entry).map_err(|e| Error::General(e.to_string()))?;
+    // serde_yaml indents with 2 spaces
+    Ok(yaml
+        .lines()
+        .filter(|line| *line != "---")
+        .map(|line| {
+            let level = indent_of(line) / 2 + 1;
+            format!("{}{}\n", " ".repeat(level * indent), line.trim_start())
         })
+        .collect())
+}
+
+///
+/// Returns 'text' with 'project' added last to the top level projects mapping.
+/// The indentation of the existing projects is used, the line endings
+/// and final newline of 'text' are kept.
+fn insert_project(text: &str, project: &GlProject) -> Result<String, Error> {
+    let parsed: Value = serde_yaml::from_str(text).map_err(|e| Error::General(e.to_string()))?;
+    let name = Value::String(project.name.clone());
+    if parsed
+        .get("projects")
+        .and_then(Value::as_mapping)
+        .is_some_and(|projects| projects.contains_key(&name))
+    {
+        return Err(Error::General(format!(
+            "Project: '{}' already exists",
+            project.name
+        )));
+    }
+    let newline = if text.contains("\r\n") { "\r\n" } else { "\n" };
+    let mut lines: Vec<String> = text.lines().map(String::from).collect();
+    let key = lines.iter().position(|line| {
+        line.strip_prefix("projects")
+            .is_some_and(|rest| rest.trim_start().starts_with(':'))
+    });
+    let key = match key {
+        Some(key) => key,
+        None => {
+            lines.push(String::from("projects:"));
+            lines.len() - 1
+        }
+    };
+    let value = lines[key]
+        .split_once(':')
+        .map(|(_, v)| v)
+        .unwrap_or_default();
+    let value = value.split(" #").next().unwrap_or_default().trim();
+    match value {
+        "" => {}
+        "{}" => lines[key] = String::from("projects:"),
+        _ => {
+            return Err(Error::General(String::from(
+                "projects must be a block mapping to add a project",
+            )))
+        }
+    }
+    // The block ends at the next line that is not indented
+    let mut end = key + 1;
+    let mut indent = None;
+    for (i, line) in lines.iter().enumerate().skip(key + 1) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if indent_of(line) == 0 {
+            if is_blank_or_comment(line) {
+                continue;
+            }
+            break;
+        }
+        if indent.is_none() && !is_blank_or_comment(line) {
+            indent = Some(indent_of(line));
+        }
+        end = i + 1;
+    }
+    let yaml = project_to_yaml(project, indent.unwrap_or(2))?;
+    lines.splice(end..end, yaml.lines().map(String::from));
+    let mut edited = lines.join(newline);
+    if text.ends_with('\n') {
+        edited += newline;
     }
+
+    let check: Value = serde_yaml::from_str(&edited).map_err(|e| Error::General(e.to_string()))?;
+    if check.get("projects").and_then(|p| p.get(&name)).is_none() {
+        return Err(Error::General(format!(
+            "Could not add project: '{}'",
+            project.name
+        )));
+    }
+    Ok(edited)
 }
 
 impl fmt::Display for GlProject {
@@ -103,7 +913,13 @@ impl fmt::Display for GlProject {
         writeln!(f, "|Fetch    |{:<70}|", self.fetch_url)?;
         writeln!(f, "|Path     |{:<70}|", &self.path.display())?;
         writeln!(f, "|Reference|{:<70}|", self.reference)?;
-        writeln!(f, "|Auto sync|{:<70}|", self.auto_sync)
+        writeln!(f, "|Auto sync|{:<70}|", self.auto_sync)?;
+        writeln!(f, "|Groups   |{:<70}|", self.groups.join(","))?;
+        writeln!(
+            f,
+            "|Strategy |{:<70}|",
+            self.sync_strategy.unwrap_or_default().to_string()
+        )
     }
 }
 
@@ -189,4 +1005,472 @@ mod tests {
         let project = projs.projects.get("bas").unwrap();
         assert_eq!(PathBuf::from("/tmp/apa"), project.path);
     }
+
+    #[test]
+    fn test_verify_sync_strategy() {
+        let yaml: &str = r"---
+            sync_strategy: rebase
+            projects:
+                foo:
+                    fetch_url: https://apa
+                    reference: main
+                bas:
+                    fetch_url: https://apa
+                    reference: main
+                    sync_strategy: reset-hard";
+
+        let projs = serde_yaml::from_str::<GlProjects>(yaml)
+            .unwrap()
+            .verify()
+            .unwrap();
+        let foo = projs.projects.get("foo").unwrap();
+        assert_eq!(Some(SyncStrategy::Rebase), foo.sync_strategy);
+        assert_eq!("foo", foo.name);
+        let bas = projs.projects.get("bas").unwrap();
+        assert_eq!(Some(SyncStrategy::ResetHard), bas.sync_strategy);
+    }
+
+    #[test]
+    fn test_verify_fetch_retries() {
+        let yaml: &str = r"---
+            default_reference: main
+            fetch_retries: 3
+            projects:
+                foo:
+                    fetch_url: https://apa
+                bas:
+                    fetch_url: https://apa
+                    fetch_retries: 0";
+
+        let projs = serde_yaml::from_str::<GlProjects>(yaml)
+            .unwrap()
+            .verify()
+            .unwrap();
+        assert_eq!(Some(3), projs.projects.get("foo").unwrap().fetch_retries);
+        assert_eq!(Some(0), projs.projects.get("bas").unwrap().fetch_retries);
+    }
+
+    #[test]
+    fn test_select_groups() {
+        let yaml: &str = r"---
+            default_reference: main
+            projects:
+                app:
+                    fetch_url: https://app
+                    groups: [apps]
+                firmware:
+                    fetch_url: https://fw
+                    groups: [firmware, apps]
+                linux:
+                    fetch_url: https://linux
+                    auto_sync: false";
+
+        let projs = serde_yaml::from_str::<GlProjects>(yaml)
+            .unwrap()
+            .verify()
+            .unwrap();
+        let select = |groups: &[&str]| {
+            let groups: Vec<String> = groups.iter().map(|g| g.to_string()).collect();
+            let mut names: Vec<String> = projs.select(&groups).into_keys().collect();
+            names.sort();
+            names
+        };
+        assert_eq!(vec!["app", "firmware"], select(&[]));
+        assert_eq!(vec!["app"], select(&["!firmware"]));
+        assert_eq!(vec!["app", "firmware", "linux"], select(&["all"]));
+        assert_eq!(vec!["linux"], select(&["all", "!apps"]));
+        assert_eq!(vec!["firmware", "linux"], select(&["firmware", "linux"]));
+        assert_eq!(vec!["linux"], select(&["notdefault"]));
+        // auto_sync: false is migrated to the group notdefault.
+        let linux = &projs.projects["linux"];
+        assert!(linux.auto_sync);
+        assert_eq!(vec!["notdefault"], linux.groups);
+        let direct = GlProject {
+            auto_sync: false,
+            ..Default::default()
+        };
+        assert!(direct.in_group("default"));
+    }
+
+    #[test]
+    fn test_verify_remotes() {
+        let yaml: &str = r"---
+            default_reference: main
+            default_remote: gitlab
+            remotes:
+                gitlab:
+                    fetch: git@gitlab.com:mike7b4/
+                    push: git@gitlab.com:mike7b4
+                upstream:
+                    fetch: https://github.com/{repo_name}.git
+            projects:
+                foo:
+                    extra_remotes: [upstream]
+                bas:
+                    remote: upstream
+                    repo_name: rust-lang/bas
+                apa:
+                    fetch_url: https://apa";
+
+        let projs = serde_yaml::from_str::<GlProjects>(yaml)
+            .unwrap()
+            .verify()
+            .unwrap();
+        let foo = projs.projects.get("foo").unwrap();
+        assert_eq!("git@gitlab.com:mike7b4/foo", foo.fetch_url);
+        assert_eq!("git@gitlab.com:mike7b4/foo", foo.push_url);
+        assert_eq!(
+            vec![GitRemote {
+                name: "upstream".into(),
+                fetch_url: "https://github.com/foo.git".into(),
+                push_url: None,
+            }],
+            foo.git_remotes
+        );
+        let bas = projs.projects.get("bas").unwrap();
+        assert_eq!("https://github.com/rust-lang/bas.git", bas.fetch_url);
+        assert!(bas.push_url.is_empty());
+        assert_eq!("https://apa", projs.projects.get("apa").unwrap().fetch_url);
+    }
+
+    #[test]
+    fn test_verify_unknown_remote() {
+        let yaml: &str = r"---
+            default_reference: main
+            projects:
+                foo:
+                    remote: nope";
+
+        let res = serde_yaml::from_str::<GlProjects>(yaml).unwrap().verify();
+        assert!(matches!(res, Err(Error::Manifest(_))));
+    }
+
+    #[test]
+    fn test_clone_depth_and_filter() {
+        let yaml: &str = r"---
+            default_reference: main
+            projects:
+                linux:
+                    fetch_url: https://linux
+                    depth: 1
+                    filter: blob:none";
+
+        let projs = serde_yaml::from_str::<GlProjects>(yaml)
+            .unwrap()
+            .verify()
+            .unwrap();
+        let linux = projs.projects.get("linux").unwrap();
+        assert_eq!(Some(1), linux.depth);
+        assert_eq!(Some(CloneFilter::BlobNone), linux.filter);
+        assert_eq!("blob:none", linux.filter.unwrap().to_string());
+    }
+
+    #[test]
+    fn test_timeout_and_env() {
+        let yaml: &str = r"---
+            default_reference: main
+            projects:
+                firmware:
+                    fetch_url: https://firmware
+                    timeout: 10m
+                    env:
+                        BOARD: nucleo";
+
+        let projs = serde_yaml::from_str::<GlProjects>(yaml)
+            .unwrap()
+            .verify()
+            .unwrap();
+        let firmware = projs.projects.get("firmware").unwrap();
+        assert_eq!(Some(Duration::from_secs(600)), firmware.timeout);
+        assert_eq!(Some(&String::from("nucleo")), firmware.env.get("BOARD"));
+    }
+
+    #[test]
+    fn test_verify_file_mappings() {
+        let verify = |files: &str| {
+            let yaml = format!(
+                "
+            default_reference: main
+            projects:
+                build:
+                    fetch_url: https://build
+                    {}",
+                files
+            );
+            serde_yaml::from_str::<GlProjects>(&yaml).unwrap().verify()
+        };
+        assert!(verify("copy_files: [{src: core/root.mk, dest: ./Makefile}]").is_ok());
+        for files in [
+            "copy_files: [{src: root.mk, dest: /etc/passwd}]",
+            "copy_files: [{src: root.mk, dest: ../Makefile}]",
+            "link_files: [{src: ../../secret, dest: secret}]",
+            "link_files: [{src: a/../../b, dest: b}]",
+            "link_files: [{src: '', dest: b}]",
+        ] {
+            assert!(
+                matches!(verify(files), Err(Error::Manifest(_))),
+                "{}",
+                files
+            );
+        }
+    }
+
+    /// Create an empty directory in the system temp dir for a test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("glrepo-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_include_and_local_manifests() {
+        let dir = test_dir("include");
+        fs::write(
+            dir.join("default.yaml"),
+            r"---
+default_reference: main
+include:
+    - common.yaml
+projects:
+    foo:
+        fetch_url: https://foo",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("common.yaml"),
+            r"---
+default_reference: develop
+projects:
+    bas:
+        fetch_url: https://bas
+    apa:
+        fetch_url: https://apa",
+        )
+        .unwrap();
+        let local = dir.join("local_manifests");
+        fs::create_dir(&local).unwrap();
+        fs::write(
+            local.join("10-local.yaml"),
+            r"---
+remove:
+    - apa
+projects:
+    foo:
+        reference: my-branch
+    new:
+        fetch_url: https://new",
+        )
+        .unwrap();
+
+        let projs = GlProjects::try_from_yaml(&dir.join("default.yaml"), Some(&local)).unwrap();
+        assert_eq!(3, projs.projects.len());
+        assert!(!projs.projects.contains_key("apa"));
+        let foo = projs.projects.get("foo").unwrap();
+        assert_eq!("https://foo", foo.fetch_url);
+        assert_eq!("my-branch", foo.reference);
+        assert_eq!("main", projs.projects.get("bas").unwrap().reference);
+        assert_eq!("https://new", projs.projects.get("new").unwrap().fetch_url);
+        let mut sorted: Vec<GlProject> = projs.projects.values().cloned().collect();
+        Sort::Manifest.sort(&mut sorted);
+        let names: Vec<&str> = sorted.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(vec!["bas", "foo", "new"], names);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let dir = test_dir("cycle");
+        fs::write(dir.join("a.yaml"), "include: [b.yaml]").unwrap();
+        fs::write(dir.join("b.yaml"), "include: [a.yaml]").unwrap();
+        match GlProjects::try_from_yaml(&dir.join("a.yaml"), None::<&Path>) {
+            Err(Error::Manifest(msg)) => {
+                assert!(msg.contains("Include cycle"));
+                assert!(msg.contains("b.yaml"));
+            }
+            _ => panic!("Expected include cycle error"),
+        }
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_include_conflict() {
+        let dir = test_dir("conflict");
+        fs::write(
+            dir.join("a.yaml"),
+            "include: [b.yaml]\nprojects:\n  foo:\n    fetch_url: https://a",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("b.yaml"),
+            "projects:\n  foo:\n    fetch_url: https://b",
+        )
+        .unwrap();
+        match GlProjects::try_from_yaml(&dir.join("a.yaml"), None::<&Path>) {
+            Err(Error::Manifest(msg)) => {
+                assert!(msg.contains("a.yaml"));
+                assert!(msg.contains("b.yaml"));
+            }
+            _ => panic!("Expected conflict error"),
+        }
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_insert_project() {
+        let project = GlProject {
+            name: String::from("new"),
+            fetch_url: String::from("https://new"),
+            groups: vec![String::from("apps")],
+            auto_sync: true,
+            ..Default::default()
+        };
+        let text = "# Projects\n\
+                    projects_dir: /src\n\
+                    projects:\n\
+                    \x20   # The first one\n\
+                    \x20   zlib:\n\
+                    \x20       fetch_url: https://zlib # pinned\n\
+                    \x20   abc:\n\
+                    \x20       fetch_url: https://abc\n\
+                    \n\
+                    # Defaults\n\
+                    default_reference: main\n";
+        assert_eq!(
+            "# Projects\n\
+             projects_dir: /src\n\
+             projects:\n\
+             \x20   # The first one\n\
+             \x20   zlib:\n\
+             \x20       fetch_url: https://zlib # pinned\n\
+             \x20   abc:\n\
+             \x20       fetch_url: https://abc\n\
+             \x20   new:\n\
+             \x20       fetch_url: \"https://new\"\n\
+             \x20       groups:\n\
+             \x20           - apps\n\
+             \n\
+             # Defaults\n\
+             default_reference: main\n",
+            insert_project(text, &project).unwrap()
+        );
+        assert!(matches!(
+            insert_project(
+                text,
+                &GlProject {
+                    name: String::from("abc"),
+                    ..Default::default()
+                }
+            ),
+            Err(Error::General(_))
+        ));
+        assert_eq!(
+            "default_reference: main\nprojects:\n  new:\n    fetch_url: \"https://new\"\n    groups:\n      - apps\n",
+            insert_project("default_reference: main\nprojects: {}\n", &project).unwrap()
+        );
+        assert_eq!(
+            "include: [a.yaml]\nprojects:\n  new:\n    fetch_url: \"https://new\"\n    groups:\n      - apps",
+            insert_project("include: [a.yaml]", &project).unwrap()
+        );
+        // Windows line endings are kept.
+        assert_eq!(
+            text.replace('\n', "\r\n").replace(
+                "    fetch_url: https://abc\r\n",
+                "    fetch_url: https://abc\r\n    \
+                     new:\r\n        fetch_url: \"https://new\"\r\n        \
+                     groups:\r\n            - apps\r\n"
+            ),
+            insert_project(&text.replace('\n', "\r\n"), &project).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_manifest_entry() {
+        let yaml: &str = r"---
+            projects_dir: /src
+            default_reference: main
+            projects: {}";
+        let projs = serde_yaml::from_str::<GlProjects>(yaml).unwrap();
+        let mut project = GlProject {
+            name: String::from("foo"),
+            path: PathBuf::from("/src/foo"),
+            reference: String::from("main"),
+            ..Default::default()
+        };
+        let entry = projs.manifest_entry(&project);
+        assert_eq!(PathBuf::new(), entry.path);
+        assert!(entry.reference.is_empty());
+        project.path = PathBuf::from("/src/apps/foo");
+        project.reference = String::from("develop");
+        let entry = projs.manifest_entry(&project);
+        assert_eq!(PathBuf::from("apps/foo"), entry.path);
+        assert_eq!("develop", entry.reference);
+        project.path = PathBuf::from("/opt/foo");
+        assert_eq!(
+            PathBuf::from("/opt/foo"),
+            projs.manifest_entry(&project).path
+        );
+    }
+
+    #[test]
+    fn test_depends_on() {
+        let manifest = |lib: &str| {
+            format!(
+                r"---
+            default_reference: main
+            projects:
+                lib:
+                    fetch_url: https://lib
+                    depends_on: [{}]
+                app:
+                    fetch_url: https://app
+                    depends_on: [lib]
+                tool:
+                    fetch_url: https://tool
+                    depends_on: [lib, app]",
+                lib
+            )
+        };
+        let verify = |yaml: &str| serde_yaml::from_str::<GlProjects>(yaml).unwrap().verify();
+        let projs = verify(&manifest("")).unwrap();
+        assert_eq!(vec!["lib", "app"], projs.projects["tool"].depends_on);
+        match verify(&manifest("nope")) {
+            Err(Error::Manifest(msg)) => assert!(msg.contains("'nope'")),
+            _ => panic!("expected unknown dependency"),
+        }
+        match verify(&manifest("tool")) {
+            Err(Error::Manifest(msg)) => {
+                assert_eq!("Dependency cycle: lib -> tool -> lib", msg)
+            }
+            _ => panic!("expected dependency cycle"),
+        }
+    }
+
+    #[test]
+    fn test_sort() {
+        let project = |name: &str, index: usize, path: &str| GlProject {
+            name: String::from(name),
+            index,
+            path: PathBuf::from(path),
+            ..Default::default()
+        };
+        let names = |projects: &[GlProject]| -> Vec<String> {
+            projects.iter().map(|p| p.name.clone()).collect()
+        };
+        let mut projects = vec![
+            project("b", 1, "/src/a"),
+            project("c", 0, "/src/c"),
+            project("a", 1, "/src/b"),
+        ];
+        Sort::Manifest.sort(&mut projects);
+        assert_eq!(vec!["c", "a", "b"], names(&projects));
+        Sort::Path.sort(&mut projects);
+        assert_eq!(vec!["b", "a", "c"], names(&projects));
+        Sort::Name.sort(&mut projects);
+        assert_eq!(vec!["a", "b", "c"], names(&projects));
+        // Projects not loaded from a manifest have index 0 and fall back to name order.
+        let mut projects = vec![project("z", 0, ""), project("y", 0, "")];
+        Sort::Manifest.sort(&mut projects);
+        assert_eq!(vec!["y", "z"], names(&projects));
+    }
 }
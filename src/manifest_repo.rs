@@ -0,0 +1,247 @@
+use crate::error::{Error, Result};
+use crate::git::{Git, OnDirty, SyncContext, SyncReport};
+use crate::manifest::GlProject;
+use crate::report::{Quiet, SharedReporter};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+///
+/// A git repository with manifests cloned by init into 'config_home'/manifests.
+/// Which repository and manifest is active is recorded in 'config_home'/manifest_repo.yaml.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestRepo {
+    pub url: String,
+    pub branch: String,
+    /// The active manifest, relative to the repository.
+    pub manifest: PathBuf,
+}
+
+impl ManifestRepo {
+    /// Retries of fetches failing with a network error, the manifest repository
+    /// is fetched before any manifest fetch_retries is read.
+    pub const FETCH_RETRIES: u32 = 3;
+
+    /// Directory the manifest repository is cloned to.
+    pub fn dir(config_home: &Path) -> PathBuf {
+        config_home.join("manifests")
+    }
+
+    fn state_file(config_home: &Path) -> PathBuf {
+        config_home.join("manifest_repo.yaml")
+    }
+
+    /// Returns true if 'manifest' is a file in the manifest repository.
+    pub fn contains(config_home: &Path, manifest: &Path) -> bool {
+        match (
+            Self::dir(config_home).canonicalize(),
+            manifest.canonicalize(),
+        ) {
+            (Ok(dir), Ok(manifest)) => manifest.starts_with(dir),
+            _ => false,
+        }
+    }
+
+    /// Path of the active manifest.
+    pub fn manifest_file(&self, config_home: &Path) -> PathBuf {
+        Self::dir(config_home).join(&self.manifest)
+    }
+
+    ///
+    /// Read which manifest repository is active, None if init has not been run.
+    /// Error
+    /// Error::Manifest
+    pub fn load(config_home: &Path) -> Result<Option<Self>> {
+        let state_file = Self::state_file(config_home);
+        if !state_file.exists() {
+            return Ok(None);
+        }
+        let s = fs::read_to_string(&state_file).map_err(|e| {
+            Error::Manifest(format!(
+                "Could not load: '{}' cause: {}",
+                state_file.display(),
+                e
+            ))
+        })?;
+        serde_yaml::from_str(&s)
+            .map(Some)
+            .map_err(|e| Error::Manifest(format!("'{}': {}", state_file.display(), e)))
+    }
+
+    ///
+    /// Clone the manifest repository, or update it if it already exists,
+    /// and make 'manifest' the active manifest.
+    /// Credentials and cancellation are taken from 'ctx'.
+    /// Error
+    /// Error::Manifest if 'manifest' is not in the repository.
+    pub fn init(
+        config_home: &Path,
+        url: &str,
+        branch: &str,
+        manifest: &Path,
+        ctx: &SyncContext,
+    ) -> Result<Self> {
+        let repo = Self {
+            url: url.into(),
+            branch: branch.into(),
+            manifest: manifest.into(),
+        };
+        // The checkout is still on the old branch when init is run with another branch.
+        if let Ok(git) = Git::open(&Self::dir(config_home)) {
+            git.detach_from_other_branch(branch)?;
+        }
+        repo.sync(config_home, OnDirty::Abort, Self::FETCH_RETRIES, ctx)?;
+        let manifest_file = repo.manifest_file(config_home);
+        if !manifest_file.is_file() {
+            return Err(Error::Manifest(format!(
+                "'{}' not found in manifest repository: {}",
+                manifest.display(),
+                url
+            )));
+        }
+        let state_file = Self::state_file(config_home);
+        let s = serde_yaml::to_string(&repo).map_err(|e| {
+            Error::Manifest(format!(
+                "Could not serialize: '{}' cause: '{}'",
+                state_file.display(),
+                e
+            ))
+        })?;
+        fs::write(&state_file, s).map_err(|e| {
+            Error::Manifest(format!(
+                "output to: '{}' cause: '{}'",
+                state_file.display(),
+                e
+            ))
+        })?;
+        Ok(repo)
+    }
+
+    /// Sync the manifest repository with the branch upstream, progress is not reported.
+    /// Fetches failing with a network error are retried 'retries' times.
+    pub fn sync(
+        &self,
+        config_home: &Path,
+        on_dirty: OnDirty,
+        retries: u32,
+        ctx: &SyncContext,
+    ) -> Result<SyncReport> {
+        let project = GlProject {
+            name: String::from("manifests"),
+            path: Self::dir(config_home),
+            fetch_url: self.url.clone(),
+            reference: self.branch.clone(),
+            fetch_retries: Some(retries),
+            ..Default::default()
+        };
+        log::info!("Sync manifest repository: {}", self.url);
+        let reporter: SharedReporter = Arc::new(Quiet);
+        let ctx = SyncContext {
+            reporter,
+            ..ctx.clone()
+        };
+        Git::sync(&project.name, &project, on_dirty, &ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_init() {
+        let dir = std::env::temp_dir().join(format!("glrepo-init-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let upstream = dir.join("upstream");
+        let config_home = dir.join("config");
+        fs::create_dir_all(&config_home).unwrap();
+        let repo = git2::Repository::init(&upstream).unwrap();
+        fs::write(upstream.join("default.yaml"), "projects: {}\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("default.yaml")).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("test", "test@localhost").unwrap();
+        let commit = repo
+            .commit(None, &sig, &sig, "manifest", &tree, &[])
+            .unwrap();
+        repo.branch("stable", &repo.find_commit(commit).unwrap(), false)
+            .unwrap();
+        let url = upstream.to_str().unwrap();
+
+        assert_eq!(None, ManifestRepo::load(&config_home).unwrap());
+        assert!(matches!(
+            ManifestRepo::init(
+                &config_home,
+                url,
+                "stable",
+                Path::new("missing.yaml"),
+                &SyncContext::default()
+            ),
+            Err(Error::Manifest(_))
+        ));
+        assert_eq!(None, ManifestRepo::load(&config_home).unwrap());
+        let manifest_repo = ManifestRepo::init(
+            &config_home,
+            url,
+            "stable",
+            Path::new("default.yaml"),
+            &SyncContext::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            Some(manifest_repo.clone()),
+            ManifestRepo::load(&config_home).unwrap()
+        );
+        assert!(manifest_repo.manifest_file(&config_home).is_file());
+        assert!(ManifestRepo::contains(
+            &config_home,
+            &manifest_repo.manifest_file(&config_home)
+        ));
+        assert!(!ManifestRepo::contains(
+            &config_home,
+            &upstream.join("default.yaml")
+        ));
+
+        // Init again on another branch.
+        fs::write(upstream.join("next.yaml"), "projects: {}\n").unwrap();
+        index.add_path(Path::new("next.yaml")).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let parent = repo.find_commit(commit).unwrap();
+        repo.commit(
+            Some("refs/heads/next"),
+            &sig,
+            &sig,
+            "next",
+            &tree,
+            &[&parent],
+        )
+        .unwrap();
+        let manifest_repo = ManifestRepo::init(
+            &config_home,
+            url,
+            "next",
+            Path::new("next.yaml"),
+            &SyncContext::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            Some(manifest_repo.clone()),
+            ManifestRepo::load(&config_home).unwrap()
+        );
+        assert!(manifest_repo.manifest_file(&config_home).is_file());
+        let checkout = git2::Repository::open(ManifestRepo::dir(&config_home)).unwrap();
+        assert_eq!(Some("refs/heads/next"), checkout.head().unwrap().name());
+        // And back to a branch that is already in the checkout.
+        ManifestRepo::init(
+            &config_home,
+            url,
+            "stable",
+            Path::new("default.yaml"),
+            &SyncContext::default(),
+        )
+        .unwrap();
+        assert_eq!(Some("refs/heads/stable"), checkout.head().unwrap().name());
+        assert!(!ManifestRepo::dir(&config_home).join("next.yaml").exists());
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,340 @@
+use glrepo::git::{ChangedFiles, ProjectStatus, SyncStatus};
+use glrepo::manifest_repo::ManifestRepo;
+use glrepo::GlProject;
+use glrepo::{Error, Result};
+use serde::Serialize;
+use std::path::PathBuf;
+
+/// Output format of the command results written to stdout.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Human readable text, export writes yaml.
+    Text,
+    Json,
+    Yaml,
+    /// Only export.
+    Toml,
+    /// google repo XML manifest, only export.
+    RepoXml,
+    /// .gitmodules file, only export.
+    GitSubmodules,
+}
+
+impl Format {
+    /// Returns true if only export can write this format.
+    pub fn is_export_only(self) -> bool {
+        matches!(self, Format::Toml | Format::RepoXml | Format::GitSubmodules)
+    }
+}
+
+/// How the progress of every project is shown on stderr.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Progress {
+    /// Dashboard with one line per running project redrawn in place.
+    Live,
+    Quiet,
+    /// One JSON object per event and line.
+    Json,
+}
+
+/// A project in the output of list.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct ProjectEntry {
+    pub name: String,
+    pub path: PathBuf,
+    pub fetch_url: String,
+    pub push_url: String,
+    pub reference: String,
+    pub groups: Vec<String>,
+    pub auto_sync: bool,
+    pub depends_on: Vec<String>,
+}
+
+impl From<&GlProject> for ProjectEntry {
+    fn from(project: &GlProject) -> Self {
+        Self {
+            name: project.name.clone(),
+            path: project.path.clone(),
+            fetch_url: project.fetch_url.clone(),
+            push_url: project.push_url.clone(),
+            reference: project.reference.clone(),
+            groups: project.groups.clone(),
+            auto_sync: project.auto_sync,
+            depends_on: project.depends_on.clone(),
+        }
+    }
+}
+
+/// Output of list and changed, projects are in the order of --sort.
+#[derive(Serialize, Debug)]
+pub struct Projects<T> {
+    pub projects: Vec<T>,
+}
+
+/// Output of path.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct PathEntry {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+/// A changed file, status holds the git status flags in lower case. Example: ["wt_modified"]
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct FileEntry {
+    pub path: String,
+    pub status: Vec<String>,
+}
+
+/// A project with changes in the output of changed, 'error' is set if the repository could not be read.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct ChangedEntry {
+    pub name: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<FileEntry>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ChangedEntry {
+    pub fn new(name: &str, files: &ChangedFiles) -> Self {
+        let mut files: Vec<FileEntry> = files
+            .iter()
+            .map(|(path, status)| FileEntry {
+                path: path.clone(),
+                status: status
+                    .iter_names()
+                    .map(|(flag, _)| flag.to_lowercase())
+                    .collect(),
+            })
+            .collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Self {
+            name: name.to_string(),
+            files,
+            error: None,
+        }
+    }
+}
+
+/// A project in the output of status, 'error' is set if the repository could not be read.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct StatusEntry {
+    pub name: String,
+    #[serde(flatten)]
+    pub status: Option<ProjectStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Render status as a table with aligned columns, projects that failed are left out.
+pub fn status_table(entries: &[StatusEntry]) -> String {
+    let count = |n: Option<usize>| n.map(|n| n.to_string()).unwrap_or_else(|| "-".into());
+    let mut rows = vec![[
+        "Project",
+        "Branch",
+        "Reference",
+        "Ahead",
+        "Behind",
+        "Stash",
+        "Dirty",
+        "Untracked",
+    ]
+    .map(String::from)];
+    for entry in entries {
+        let status = match &entry.status {
+            Some(status) => status,
+            None => continue,
+        };
+        let branch = match (&status.branch, &status.head) {
+            (Some(branch), _) => branch.clone(),
+            (None, Some(head)) => format!("(detached {})", &head[..7.min(head.len())]),
+            (None, None) => String::from("(no commits)"),
+        };
+        rows.push([
+            entry.name.clone(),
+            branch,
+            status.reference.clone(),
+            count(status.ahead),
+            count(status.behind),
+            status.stashes.to_string(),
+            status.dirty.to_string(),
+            status.untracked.to_string(),
+        ]);
+    }
+    let mut widths = [0; 8];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut table = String::new();
+    for row in &rows {
+        let mut line = String::new();
+        for (i, cell) in row.iter().enumerate() {
+            // Text columns are left aligned and counters right aligned.
+            if i < 3 {
+                line += &format!("{:<width$}  ", cell, width = widths[i]);
+            } else {
+                line += &format!("{:>width$}  ", cell, width = widths[i]);
+            }
+        }
+        table += line.trim_end();
+        table += "\n";
+    }
+    table
+}
+
+/// Result of a command run on one project.
+/// 'status' and 'attempts' are only set by sync and 'error' only if the command failed.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct ProjectResult {
+    pub project: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<SyncStatus>,
+    /// Attempts needed by the most retried fetch, only set by sync.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attempts: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Result of a command run on every selected project, in the order of --sort.
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub command: String,
+    pub results: Vec<ProjectResult>,
+}
+
+/// Output of init.
+#[derive(Serialize, Debug)]
+pub struct ManifestEntry {
+    pub manifest: PathBuf,
+    #[serde(flatten)]
+    pub repo: ManifestRepo,
+}
+
+/// Output of lock.
+#[derive(Serialize, Debug)]
+pub struct LockEntry {
+    pub lock_file: PathBuf,
+}
+
+/// Output when glrepo fails before any project result could be reported.
+#[derive(Serialize, Debug)]
+pub struct ErrorReport {
+    pub error: String,
+}
+
+/// Serialize 'value' to a JSON or YAML document.
+/// Error
+/// Error::General
+pub fn to_string<T: Serialize>(format: Format, value: &T) -> Result<String> {
+    match format {
+        Format::Json => {
+            serde_json::to_string_pretty(value).map_err(|e| Error::General(e.to_string()))
+        }
+        Format::Yaml => serde_yaml::to_string(value).map_err(|e| Error::General(e.to_string())),
+        Format::Text => Err(Error::General(String::from(
+            "Text output is written by each command",
+        ))),
+        Format::Toml | Format::RepoXml | Format::GitSubmodules => Err(Error::General(
+            String::from("Only export writes toml, repo-xml and git-submodules"),
+        )),
+    }
+}
+
+/// Write 'value' to stdout as a JSON or YAML document.
+pub fn print<T: Serialize>(format: Format, value: &T) -> Result<()> {
+    println!("{}", to_string(format, value)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_status_table() {
+        let entries = vec![
+            StatusEntry {
+                name: String::from("batchecker"),
+                status: Some(ProjectStatus {
+                    branch: Some(String::from("main")),
+                    reference: String::from("main"),
+                    head: Some(String::from("0123456789abcdef0123456789abcdef01234567")),
+                    ahead: Some(2),
+                    behind: Some(10),
+                    untracked: 1,
+                    ..Default::default()
+                }),
+                error: None,
+            },
+            StatusEntry {
+                name: String::from("linux"),
+                status: Some(ProjectStatus {
+                    reference: String::from("v6.1"),
+                    head: Some(String::from("0123456789abcdef0123456789abcdef01234567")),
+                    detached: true,
+                    stashes: 1,
+                    ..Default::default()
+                }),
+                error: None,
+            },
+            StatusEntry {
+                name: String::from("missing"),
+                status: None,
+                error: Some(String::from("not found")),
+            },
+        ];
+        assert_eq!(
+            "Project     Branch              Reference  Ahead  Behind  Stash  Dirty  Untracked\n\
+             batchecker  main                main           2      10      0      0          1\n\
+             linux       (detached 0123456)  v6.1           -       -      1      0          0\n",
+            status_table(&entries)
+        );
+        assert_eq!(
+            r#"{"name":"missing","error":"not found"}"#,
+            serde_json::to_string(&entries[2]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_changed_entry() {
+        let entry = ChangedEntry {
+            name: String::from("missing"),
+            files: vec![],
+            error: Some(String::from("not found")),
+        };
+        assert_eq!(
+            r#"{"name":"missing","error":"not found"}"#,
+            serde_json::to_string(&entry).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_report_schema() {
+        let report = Report {
+            command: String::from("sync"),
+            results: vec![
+                ProjectResult {
+                    project: String::from("a"),
+                    ok: true,
+                    status: Some(SyncStatus::SkippedDirty),
+                    attempts: Some(2),
+                    error: None,
+                },
+                ProjectResult {
+                    project: String::from("b"),
+                    ok: false,
+                    status: None,
+                    attempts: None,
+                    error: Some(String::from("failed")),
+                },
+            ],
+        };
+        assert_eq!(
+            r#"{"command":"sync","results":[{"project":"a","ok":true,"status":"skipped_dirty","attempts":2},{"project":"b","ok":false,"error":"failed"}]}"#,
+            serde_json::to_string(&report).unwrap()
+        );
+    }
+}
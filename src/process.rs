@@ -1,39 +1,350 @@
+use crate::cancel::CancelToken;
 use crate::error::{Error, Result};
-use std::path::Path;
-use std::process::{Command, Stdio};
+use crate::manifest::GlProject;
+use colored::*;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
 use std::{thread, time};
 
+/// How the output of for-each commands is shown, selected on the command line.
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    /// Output is written directly to the terminal.
+    Inherit,
+    /// Every line is prefixed with the project name.
+    Prefix,
+    /// Output is buffered and printed when the command has finished.
+    Buffer,
+    /// Output is written to <log-dir>/<project>.log
+    Log,
+}
+
+/// How the stdout and stderr of a child process is handled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    Inherit,
+    Prefix,
+    Buffer,
+    /// Write to a log file per project in the directory.
+    Log(PathBuf),
+}
+
+impl Output {
+    ///
+    /// 'log_dir' is required for OutputMode::Log.
+    /// Error
+    /// Error::General if 'mode' is OutputMode::Log and 'log_dir' is None.
+    pub fn new(mode: OutputMode, log_dir: Option<&Path>) -> Result<Self> {
+        match (mode, log_dir) {
+            (OutputMode::Inherit, _) => Ok(Output::Inherit),
+            (OutputMode::Prefix, _) => Ok(Output::Prefix),
+            (OutputMode::Buffer, _) => Ok(Output::Buffer),
+            (OutputMode::Log, Some(dir)) => Ok(Output::Log(dir.to_path_buf())),
+            (OutputMode::Log, None) => Err(Error::General(String::from(
+                "Output log requires a log directory",
+            ))),
+        }
+    }
+
+    /// Set up stdout and stderr of 'command'.
+    fn configure(
+        &self,
+        project_name: &str,
+        command: &mut Command,
+        stdout_to_stderr: bool,
+    ) -> io::Result<()> {
+        match self {
+            Output::Inherit if stdout_to_stderr => {
+                command.stdout(io::stderr()).stderr(Stdio::inherit());
+            }
+            Output::Inherit => {
+                command.stdout(Stdio::inherit()).stderr(Stdio::inherit());
+            }
+            Output::Prefix | Output::Buffer => {
+                command.stdout(Stdio::piped()).stderr(Stdio::piped());
+            }
+            Output::Log(dir) => {
+                fs::create_dir_all(dir)?;
+                let path = log_file(dir, project_name);
+                log::info!("Project: '{}' Log: '{}'", project_name, path.display());
+                let file = fs::File::create(path)?;
+                command.stderr(file.try_clone()?).stdout(file);
+            }
+        }
+        Ok(())
+    }
+
+    /// Start reading the pipes of 'child'.
+    fn capture(&self, project_name: &str, child: &mut Child, stdout_to_stderr: bool) -> Capture {
+        let mut capture = Capture {
+            project_name: project_name.into(),
+            readers: vec![],
+            buffers: None,
+            stdout_to_stderr,
+        };
+        match self {
+            Output::Prefix => {
+                let prefix = format!("{}: ", project_name.color(project_color(project_name)));
+                if let Some(stdout) = child.stdout.take() {
+                    let prefix = prefix.clone();
+                    capture.readers.push(thread::spawn(move || {
+                        if stdout_to_stderr {
+                            prefix_lines(stdout, &prefix, io::stderr())
+                        } else {
+                            prefix_lines(stdout, &prefix, io::stdout())
+                        }
+                    }));
+                }
+                if let Some(stderr) = child.stderr.take() {
+                    capture.readers.push(thread::spawn(move || {
+                        prefix_lines(stderr, &prefix, io::stderr())
+                    }));
+                }
+            }
+            Output::Buffer => {
+                let buffers: (Buffer, Buffer) = Default::default();
+                if let Some(stdout) = child.stdout.take() {
+                    let buffer = Arc::clone(&buffers.0);
+                    capture
+                        .readers
+                        .push(thread::spawn(move || collect(stdout, &buffer)));
+                }
+                if let Some(stderr) = child.stderr.take() {
+                    let buffer = Arc::clone(&buffers.1);
+                    capture
+                        .readers
+                        .push(thread::spawn(move || collect(stderr, &buffer)));
+                }
+                capture.buffers = Some(buffers);
+            }
+            Output::Inherit | Output::Log(_) => {}
+        }
+        capture
+    }
+}
+
+/// Output shared between a reader thread and the thread waiting for the process.
+type Buffer = Arc<Mutex<Vec<u8>>>;
+
+/// Output read from a child process.
+struct Capture {
+    project_name: String,
+    readers: Vec<thread::JoinHandle<()>>,
+    /// Buffered stdout and stderr.
+    buffers: Option<(Buffer, Buffer)>,
+    /// Buffered stdout is written to stderr.
+    stdout_to_stderr: bool,
+}
+
+impl Capture {
+    /// Wait for all output and print buffered output.
+    /// If 'wait' is false the output read so far is printed, since a killed
+    /// process may have children that still keeps the pipes open.
+    fn finish(self, wait: bool) {
+        if wait {
+            for reader in self.readers {
+                reader.join().ok();
+            }
+        }
+        if let Some((stdout, stderr)) = self.buffers {
+            let header = format!("==> {} <==\n", self.project_name.bold());
+            // Lock stdout before stderr, the same order in every thread.
+            let mut out = io::stdout().lock();
+            let mut err = io::stderr().lock();
+            let stdout = stdout.lock().unwrap();
+            if self.stdout_to_stderr {
+                write_buffer(&header, &stdout, &mut err);
+            } else {
+                write_buffer(&header, &stdout, &mut out);
+            }
+            write_buffer(&header, &stderr.lock().unwrap(), &mut err);
+        }
+    }
+}
+
+/// Write 'header' and 'buffer' to 'writer', nothing if 'buffer' is empty.
+fn write_buffer<W: Write>(header: &str, buffer: &[u8], writer: &mut W) {
+    if !buffer.is_empty() {
+        writer.write_all(header.as_bytes()).ok();
+        writer.write_all(buffer).ok();
+        writer.flush().ok();
+    }
+}
+
+/// Returns the log file of 'project_name' in 'dir'.
+fn log_file(dir: &Path, project_name: &str) -> PathBuf {
+    dir.join(format!("{}.log", project_name.replace('/', "_")))
+}
+
+/// The same project always gets the same color.
+fn project_color(project_name: &str) -> Color {
+    const COLORS: [Color; 6] = [
+        Color::Cyan,
+        Color::Green,
+        Color::Yellow,
+        Color::Blue,
+        Color::Magenta,
+        Color::Red,
+    ];
+    let sum: usize = project_name.bytes().map(usize::from).sum();
+    COLORS[sum % COLORS.len()]
+}
+
+/// Copy every line from 'reader' to 'writer' with 'prefix' before it.
+/// A line is written with one call so lines from other projects are not mixed in.
+fn prefix_lines<R: Read, W: Write>(reader: R, prefix: &str, mut writer: W) {
+    let mut reader = BufReader::new(reader);
+    let mut line = vec![];
+    loop {
+        line.clear();
+        line.extend_from_slice(prefix.as_bytes());
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                if !line.ends_with(b"\n") {
+                    line.push(b'\n');
+                }
+                writer.write_all(&line).ok();
+            }
+        }
+    }
+}
+
+/// Append everything from 'reader' to 'buffer'.
+fn collect<R: Read>(mut reader: R, buffer: &Mutex<Vec<u8>>) {
+    let mut chunk = [0; 4096];
+    loop {
+        match reader.read(&mut chunk) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => buffer.lock().unwrap().extend_from_slice(&chunk[..n]),
+        }
+    }
+}
+
+/// When a shell command is terminated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout {
+    /// Max run time, None means no limit.
+    pub limit: Option<time::Duration>,
+    /// Time between SIGTERM and SIGKILL.
+    pub grace: time::Duration,
+}
+
+///
+/// Terminate the process group of 'child'.
+/// SIGTERM is sent to every process in the group and if any is
+/// still running after 'grace' they get SIGKILL.
+#[cfg(unix)]
+fn terminate(child: &mut Child, grace: time::Duration) -> io::Result<()> {
+    // The child is the leader of its own process group, see spawn_shell_and_wait.
+    let group = -(child.id() as libc::pid_t);
+    let signal = |signal| {
+        // SAFETY: kill has no memory safety requirements.
+        unsafe { libc::kill(group, signal) == 0 }
+    };
+    signal(libc::SIGTERM);
+    let now = time::Instant::now();
+    while now.elapsed() < grace {
+        // Reap the shell, else the group exists as long as it is a zombie.
+        child.try_wait()?;
+        if !signal(0) {
+            return Ok(());
+        }
+        thread::sleep(time::Duration::from_millis(50));
+    }
+    signal(libc::SIGKILL);
+    child.wait().map(|_| ())
+}
+
+#[cfg(not(unix))]
+fn terminate(child: &mut Child, _grace: time::Duration) -> io::Result<()> {
+    child.kill()?;
+    child.wait().map(|_| ())
+}
+
+///
+/// Environment variables describing 'project' for commands run in it.
+/// The project env map is added first so the GLREPO_ variables can not be overridden.
+/// Arguments
+/// 'manifest' Path to the manifest file.
+/// 'index' Position of the project starting at 1 of 'count' projects.
+pub fn project_env(
+    project: &GlProject,
+    manifest: &Path,
+    index: usize,
+    count: usize,
+) -> Vec<(String, String)> {
+    let mut env: Vec<(String, String)> = project
+        .env
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    env.extend(
+        [
+            ("GLREPO_PROJECT", project.name.clone()),
+            ("GLREPO_PATH", project.path.display().to_string()),
+            ("GLREPO_FETCH_URL", project.fetch_url.clone()),
+            ("GLREPO_REFERENCE", project.reference.clone()),
+            ("GLREPO_GROUPS", project.groups.join(",")),
+            ("GLREPO_MANIFEST", manifest.display().to_string()),
+            ("GLREPO_INDEX", index.to_string()),
+            ("GLREPO_COUNT", count.to_string()),
+        ]
+        .map(|(k, v)| (k.to_string(), v)),
+    );
+    env
+}
+
 ///
 /// Spawn a shell process and wait for it to end.
-/// The stdout is **not** handled by the application.
-/// It's up to the child process to handle the stdout/error if needed.
+/// 'output' decides how the stdout and stderr of the process is shown.
 /// Arguments
 /// 'project_name' Used when print error message if it fails to start or timeout
 /// 'working_directory' Spawn shell in the specified working directory.
 /// 'args' Arguments to pass to the shell process.
-/// 'timeout' as Duration
+/// 'timeout' When the process and its children are terminated.
+/// 'output' Where the output of the process goes.
+/// 'stdout_to_stderr' Write stdout of the process to stderr, unless 'output' is a log file.
+/// 'env' Environment variables added to the process environment.
+/// 'cancel' When set the process is terminated as on timeout.
 /// Errors
-/// Error::ShellCommand or Error::ShellCommandTimeout
+/// Error::ShellCommand, Error::ShellCommandTimeout or Error::Interrupted
+#[allow(clippy::too_many_arguments)]
 pub fn spawn_shell_and_wait(
     project_name: &str,
     working_directory: &Path,
     args: String,
-    timeout: time::Duration,
+    timeout: Timeout,
+    output: &Output,
+    stdout_to_stderr: bool,
+    env: &[(String, String)],
+    cancel: &CancelToken,
 ) -> Result<()> {
     let now = time::Instant::now();
-    match Command::new("sh")
+    let mut command = Command::new("sh");
+    command
         .current_dir(working_directory)
         .stdin(Stdio::null())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
+        .envs(env.iter().map(|(k, v)| (k, v)))
         .arg("-c")
-        .arg(&args)
-        .spawn()
-    {
+        .arg(&args);
+    // Run in a new process group so the children of the shell can be terminated too.
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    if let Err(e) = output.configure(project_name, &mut command, stdout_to_stderr) {
+        return Err(Error::ShellCommand(project_name.into(), args, e));
+    }
+    match command.spawn() {
         Ok(mut child) => {
+            let capture = output.capture(project_name, &mut child, stdout_to_stderr);
             loop {
                 match child.try_wait() {
                     Ok(Some(status)) => {
+                        capture.finish(true);
                         let res = match status.code() {
                             Some(0) => {
                                 log::info!(
@@ -52,20 +363,250 @@ pub fn spawn_shell_and_wait(
                     }
                     Ok(None) => { /* Still running */ }
                     Err(e) => {
+                        capture.finish(false);
                         return Err(Error::ShellCommand(project_name.into(), args, e));
                     }
                 }
                 thread::sleep(std::time::Duration::from_millis(50));
-                if now.elapsed() >= timeout {
+                if cancel.is_cancelled()
+                    || timeout.limit.is_some_and(|limit| now.elapsed() >= limit)
+                {
                     break;
                 }
             }
             // Still running
-            if let Err(e) = child.kill() {
+            log::warn!(
+                "Project: '{}' Command: '{}' {}, terminating.",
+                project_name,
+                args,
+                if cancel.is_cancelled() {
+                    "interrupted"
+                } else {
+                    "timeout"
+                }
+            );
+            let killed = terminate(&mut child, timeout.grace);
+            capture.finish(false);
+            if let Err(e) = killed {
                 return Err(Error::ShellCommand(project_name.into(), args, e));
             }
+            if cancel.is_cancelled() {
+                return Err(Error::Interrupted(project_name.into()));
+            }
             Err(Error::ShellCommandTimeout(project_name.into(), args))
         }
         Err(e) => Err(Error::ShellCommand(project_name.into(), args, e)),
     }
 }
+
+/// Time between SIGTERM and SIGKILL when a program run by spawn_and_wait is cancelled.
+const CANCEL_GRACE: time::Duration = time::Duration::from_secs(2);
+
+///
+/// Spawn 'program' with 'args' and wait for it to end.
+/// The stdout of the program is written to stderr, so the stdout of the
+/// application only holds command results.
+/// Every line the program writes to stderr, lines ending with '\r' included, is passed
+/// to 'on_stderr', the lines it returns false for are kept for Error::CommandFailed.
+/// Arguments
+/// 'project_name' Used when print error message if it fails to start or exits with failure
+/// 'working_directory' Spawn process in the specified working directory.
+/// 'program' Program to run.
+/// 'args' Arguments to pass to the program.
+/// 'env' Environment variables added to the process environment.
+/// 'cancel' When set the program is terminated.
+/// Errors
+/// Error::ShellCommand, Error::CommandFailed or Error::Interrupted
+pub fn spawn_and_wait<F>(
+    project_name: &str,
+    working_directory: &Path,
+    program: &str,
+    args: &[String],
+    env: &[(String, String)],
+    cancel: &CancelToken,
+    on_stderr: F,
+) -> Result<()>
+where
+    F: FnMut(&str) -> bool + Send + 'static,
+{
+    let command_line = format!("{} {}", program, args.join(" "));
+    log::debug!("Project: '{}' Command: '{}'", project_name, command_line);
+    let mut command = Command::new(program);
+    command
+        .current_dir(working_directory)
+        .stdin(Stdio::null())
+        .stdout(std::io::stderr())
+        .stderr(Stdio::piped())
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .args(args);
+    // Run in a new process group so the children of the program can be terminated too.
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    let mut child = command
+        .spawn()
+        .map_err(|e| Error::ShellCommand(project_name.into(), command_line.clone(), e))?;
+    let stderr = child.stderr.take();
+    let reader = thread::spawn(move || {
+        stderr
+            .map(|stderr| split_lines(stderr, on_stderr))
+            .unwrap_or_default()
+    });
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) => { /* Still running */ }
+            Err(e) => return Err(Error::ShellCommand(project_name.into(), command_line, e)),
+        }
+        if cancel.is_cancelled() {
+            log::warn!(
+                "Project: '{}' Command: '{}' interrupted, terminating.",
+                project_name,
+                command_line
+            );
+            terminate(&mut child, CANCEL_GRACE)
+                .map_err(|e| Error::ShellCommand(project_name.into(), command_line, e))?;
+            return Err(Error::Interrupted(project_name.into()));
+        }
+        thread::sleep(time::Duration::from_millis(50));
+    };
+    let stderr = reader.join().unwrap_or_default();
+    match status.code() {
+        Some(0) => Ok(()),
+        code => Err(Error::CommandFailed(
+            project_name.into(),
+            command_line,
+            code.unwrap_or(0xDEAD),
+            stderr,
+        )),
+    }
+}
+
+///
+/// Call 'on_line' for every line from 'reader', a line ends with '\n' or '\r'.
+/// Returns the lines 'on_line' returned false for.
+fn split_lines<R: Read, F: FnMut(&str) -> bool>(reader: R, mut on_line: F) -> String {
+    let mut kept = String::new();
+    let mut line = vec![];
+    let mut end_of_line = |line: &mut Vec<u8>| {
+        let text = String::from_utf8_lossy(line);
+        if !text.trim().is_empty() && !on_line(&text) {
+            kept.push_str(&text);
+            kept.push('\n');
+        }
+        line.clear();
+    };
+    for byte in BufReader::new(reader).bytes() {
+        match byte {
+            Ok(b'\n' | b'\r') => end_of_line(&mut line),
+            Ok(byte) => line.push(byte),
+            Err(_) => break,
+        }
+    }
+    end_of_line(&mut line);
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_output_new() {
+        assert_eq!(
+            Output::Buffer,
+            Output::new(OutputMode::Buffer, None).unwrap()
+        );
+        assert_eq!(
+            Output::Log(PathBuf::from("/tmp/logs")),
+            Output::new(OutputMode::Log, Some(Path::new("/tmp/logs"))).unwrap()
+        );
+        assert!(matches!(
+            Output::new(OutputMode::Log, None),
+            Err(Error::General(_))
+        ));
+    }
+
+    #[test]
+    fn test_prefix_lines() {
+        let mut output = vec![];
+        prefix_lines("one\n\ntwo\nno newline".as_bytes(), "p: ", &mut output);
+        assert_eq!(
+            "p: one\np: \np: two\np: no newline\n",
+            String::from_utf8(output).unwrap()
+        );
+        let mut output = vec![];
+        prefix_lines("".as_bytes(), "p: ", &mut output);
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_collect() {
+        let buffer = Mutex::new(b"start\n".to_vec());
+        let input = "x".repeat(10000);
+        collect(input.as_bytes(), &buffer);
+        let buffer = buffer.into_inner().unwrap();
+        assert_eq!(6 + 10000, buffer.len());
+        assert!(buffer.starts_with(b"start\nxxx"));
+    }
+
+    #[test]
+    fn test_write_buffer() {
+        let mut output = vec![];
+        write_buffer("==> p <==\n", b"", &mut output);
+        assert!(output.is_empty());
+        write_buffer("==> p <==\n", b"line\n", &mut output);
+        assert_eq!("==> p <==\nline\n", String::from_utf8(output).unwrap());
+    }
+
+    #[test]
+    fn test_split_lines() {
+        let input = "Receiving objects:  50% (1/2)\rReceiving objects: 100% (2/2), done.\nfatal: bad\n\nend";
+        let mut progress = vec![];
+        let kept = split_lines(input.as_bytes(), |line| {
+            let is_progress = line.contains("% (");
+            if is_progress {
+                progress.push(line.to_string());
+            }
+            is_progress
+        });
+        assert_eq!("fatal: bad\nend\n", kept);
+        assert_eq!(
+            vec![
+                "Receiving objects:  50% (1/2)",
+                "Receiving objects: 100% (2/2), done."
+            ],
+            progress
+        );
+    }
+
+    #[test]
+    fn test_project_env() {
+        let mut project = GlProject {
+            name: String::from("batchecker"),
+            path: PathBuf::from("/src/batchecker"),
+            fetch_url: String::from("https://batchecker"),
+            reference: String::from("main"),
+            groups: vec![String::from("tools"), String::from("rust")],
+            ..Default::default()
+        };
+        project
+            .env
+            .insert(String::from("TARGET"), String::from("thumbv7em"));
+        project
+            .env
+            .insert(String::from("GLREPO_PROJECT"), String::from("other"));
+        let env = project_env(&project, Path::new("/glrepo/default.yaml"), 2, 3);
+        let get = |key: &str| {
+            env.iter()
+                .rev()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(Some("thumbv7em"), get("TARGET"));
+        assert_eq!(Some("batchecker"), get("GLREPO_PROJECT"));
+        assert_eq!(Some("/src/batchecker"), get("GLREPO_PATH"));
+        assert_eq!(Some("tools,rust"), get("GLREPO_GROUPS"));
+        assert_eq!(Some("/glrepo/default.yaml"), get("GLREPO_MANIFEST"));
+        assert_eq!(Some("2"), get("GLREPO_INDEX"));
+        assert_eq!(Some("3"), get("GLREPO_COUNT"));
+    }
+}
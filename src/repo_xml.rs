@@ -0,0 +1,528 @@
+use crate::error::Error;
+use crate::export;
+use crate::manifest::GlProjects;
+use roxmltree::Node;
+use serde_yaml::{Mapping, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+
+/// Project attributes that are mapped to manifest fields.
+const PROJECT_ATTRIBUTES: [&str; 6] = [
+    "name",
+    "path",
+    "revision",
+    "groups",
+    "remote",
+    "clone-depth",
+];
+
+/// Returns 'revision' without refs/heads/ or refs/tags/.
+fn reference(revision: &str) -> &str {
+    revision
+        .strip_prefix("refs/heads/")
+        .or_else(|| revision.strip_prefix("refs/tags/"))
+        .unwrap_or(revision)
+}
+
+/// Returns the src and dest attributes of copyfile and linkfile as a mapping.
+fn file_mapping(node: Node) -> Value {
+    let mut m = Mapping::new();
+    m.insert(
+        "src".into(),
+        node.attribute("src").unwrap_or_default().into(),
+    );
+    m.insert(
+        "dest".into(),
+        node.attribute("dest").unwrap_or_default().into(),
+    );
+    Value::Mapping(m)
+}
+
+/// Append 'value' to the sequence 'key' in 'm'.
+fn push(m: &mut Mapping, key: &str, value: Value) {
+    let key = Value::from(key);
+    match m.get_mut(&key) {
+        Some(Value::Sequence(seq)) => seq.push(value),
+        _ => {
+            m.insert(key, Value::Sequence(vec![value]));
+        }
+    }
+}
+
+///
+/// Returns the URL of the origin remote of the git repository containing 'path',
+/// relative fetch URLs in a repo manifest are resolved against it.
+pub fn manifest_url(path: &Path) -> Option<String> {
+    let dir = path.parent().filter(|p| !p.as_os_str().is_empty())?;
+    let repo = git2::Repository::discover(dir).ok()?;
+    let remote = repo.find_remote("origin").ok()?;
+    remote.url().map(String::from)
+}
+
+///
+/// Resolve a relative 'fetch' URL against 'manifest_url' the way repo does,
+/// '..' is the directory containing the manifest repository.
+/// Returns None if 'fetch' goes above the host or the root.
+fn resolve_fetch(manifest_url: &str, fetch: &str) -> Option<String> {
+    // Split into the part that is kept, scheme://host or user@host:, and the path.
+    let (prefix, path) = match manifest_url.find("://") {
+        Some(i) => {
+            let end = manifest_url[i + 3..]
+                .find('/')
+                .map_or(manifest_url.len(), |j| i + 3 + j);
+            manifest_url.split_at(end)
+        }
+        None => match manifest_url.find(':') {
+            Some(i) if !manifest_url[..i].contains('/') => manifest_url.split_at(i + 1),
+            _ => ("", manifest_url),
+        },
+    };
+    let mut segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    // The manifest repository itself.
+    segments.pop()?;
+    for part in fetch.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            part => segments.push(part),
+        }
+    }
+    let path = segments.join("/");
+    Some(if prefix.ends_with(':') {
+        format!("{}{}", prefix, path)
+    } else if prefix.is_empty() && !manifest_url.starts_with('/') {
+        path
+    } else {
+        format!("{}/{}", prefix, path)
+    })
+}
+
+///
+/// Convert a google repo XML manifest to the YAML mapping of a glrepo manifest.
+/// remote, default, project, include and remove-project are mapped,
+/// other elements and attributes are reported and ignored.
+///
+/// Projects are named by the repository name, a repository checked out
+/// at more than one path is named by the path for the others.
+/// Relative fetch URLs are resolved against 'manifest_url'.
+/// Error
+/// Error::Manifest if 'xml' is not a repo manifest, a relative fetch URL can not be resolved
+/// or two projects have the same name and path.
+pub fn to_mapping(path: &Path, xml: &str, manifest_url: Option<&str>) -> Result<Mapping, Error> {
+    let err = |e: String| Error::Manifest(format!("'{}': {}", path.display(), e));
+    let doc = roxmltree::Document::parse(xml).map_err(|e| err(e.to_string()))?;
+    let root = doc.root_element();
+    if root.tag_name().name() != "manifest" {
+        return Err(err(String::from("Expected a <manifest> element")));
+    }
+    let unsupported = |node: Node| {
+        log::warn!(
+            "'{}': Unsupported element <{}> ignored",
+            path.display(),
+            node.tag_name().name()
+        );
+    };
+
+    let mut manifest = Mapping::new();
+    let mut remotes = Mapping::new();
+    // Default revision of each remote.
+    let mut revisions = HashMap::new();
+    let mut default_remote = None;
+    for node in root.children().filter(Node::is_element) {
+        match node.tag_name().name() {
+            "remote" => {
+                let name = node
+                    .attribute("name")
+                    .ok_or_else(|| err(String::from("<remote> without name")))?;
+                let mut fetch = node.attribute("fetch").unwrap_or_default().to_string();
+                if fetch.starts_with('.') {
+                    let url = manifest_url.ok_or_else(|| {
+                        err(format!(
+                            "remote: {} has a fetch URL relative to the manifest URL: '{}', \
+                             but the manifest is not in a git repository with an origin remote",
+                            name, fetch
+                        ))
+                    })?;
+                    fetch = resolve_fetch(url, &fetch).ok_or_else(|| {
+                        err(format!(
+                            "remote: {} fetch URL: '{}' can not be resolved against the manifest URL: '{}'",
+                            name, fetch, url
+                        ))
+                    })?;
+                }
+                let mut remote = Mapping::new();
+                remote.insert("fetch".into(), fetch.into());
+                if let Some(push) = node.attribute("pushurl") {
+                    remote.insert("push".into(), push.into());
+                }
+                if let Some(revision) = node.attribute("revision") {
+                    revisions.insert(name, reference(revision));
+                }
+                remotes.insert(name.into(), Value::Mapping(remote));
+            }
+            "default" => {
+                if let Some(remote) = node.attribute("remote") {
+                    default_remote = Some(remote);
+                    manifest.insert("default_remote".into(), remote.into());
+                }
+                if let Some(revision) = node.attribute("revision") {
+                    manifest.insert("default_reference".into(), reference(revision).into());
+                }
+            }
+            _ => {}
+        }
+    }
+    if !remotes.is_empty() {
+        manifest.insert("remotes".into(), Value::Mapping(remotes));
+    }
+
+    let mut projects = Mapping::new();
+    // Checkout path of each project.
+    let mut paths = HashSet::new();
+    for node in root.children().filter(Node::is_element) {
+        match node.tag_name().name() {
+            "remote" | "default" => {}
+            "include" => push(
+                &mut manifest,
+                "include",
+                node.attribute("name").unwrap_or_default().into(),
+            ),
+            "remove-project" => push(
+                &mut manifest,
+                "remove",
+                node.attribute("name").unwrap_or_default().into(),
+            ),
+            "project" => {
+                let name = node
+                    .attribute("name")
+                    .ok_or_else(|| err(String::from("<project> without name")))?;
+                let key = match node.attribute("path") {
+                    Some(path) if projects.contains_key(&Value::from(name)) => path,
+                    _ => name,
+                };
+                let checkout = node.attribute("path").unwrap_or(name);
+                if !paths.insert(checkout) || projects.contains_key(&Value::from(key)) {
+                    return Err(err(format!(
+                        "project: {} path: {} is already in the manifest",
+                        name, checkout
+                    )));
+                }
+                let mut project = Mapping::new();
+                if key != name {
+                    project.insert("repo_name".into(), name.into());
+                }
+                if let Some(path) = node.attribute("path") {
+                    project.insert("path".into(), path.into());
+                }
+                let remote = node.attribute("remote");
+                let revision = node.attribute("revision").map(reference).or_else(|| {
+                    remote
+                        .or(default_remote)
+                        .and_then(|r| revisions.get(r).copied())
+                });
+                if let Some(revision) = revision {
+                    project.insert("reference".into(), revision.into());
+                }
+                if let Some(remote) = remote {
+                    project.insert("remote".into(), remote.into());
+                }
+                if let Some(groups) = node.attribute("groups") {
+                    let groups = groups
+                        .split(|c: char| c == ',' || c.is_whitespace())
+                        .filter(|g| !g.is_empty())
+                        .map(Value::from)
+                        .collect();
+                    project.insert("groups".into(), Value::Sequence(groups));
+                }
+                if let Some(depth) = node.attribute("clone-depth") {
+                    let depth = depth
+                        .parse::<u32>()
+                        .map_err(|e| err(format!("project: {} clone-depth: {}", name, e)))?;
+                    project.insert("depth".into(), depth.into());
+                }
+                for attribute in node.attributes() {
+                    if !PROJECT_ATTRIBUTES.contains(&attribute.name()) {
+                        log::info!(
+                            "'{}': project: {} attribute {} ignored",
+                            path.display(),
+                            name,
+                            attribute.name()
+                        );
+                    }
+                }
+                for child in node.children().filter(Node::is_element) {
+                    match child.tag_name().name() {
+                        "copyfile" => push(&mut project, "copy_files", file_mapping(child)),
+                        "linkfile" => push(&mut project, "link_files", file_mapping(child)),
+                        _ => unsupported(child),
+                    }
+                }
+                projects.insert(key.into(), Value::Mapping(project));
+            }
+            _ => unsupported(node),
+        }
+    }
+    manifest.insert("projects".into(), Value::Mapping(projects));
+    Ok(manifest)
+}
+
+/// Escape 's' for an XML attribute value.
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Returns a fetch URL split into the remote base URL and the repository name.
+fn split_url(url: &str) -> Option<(&str, &str)> {
+    url.trim_end_matches('/')
+        .rsplit_once('/')
+        .filter(|(base, name)| !base.is_empty() && !name.is_empty())
+}
+
+///
+/// Convert a manifest to a google repo XML manifest.
+/// Every distinct base of the fetch and push URLs becomes a remote,
+/// named by the manifest remote with the same URL if there is one.
+/// Paths are relative to the projects_dir.
+/// Error
+/// Error::General if a fetch URL has no repository name, example: git@host:repo.git,
+/// or a project is outside the projects_dir.
+pub fn to_xml(projects: &GlProjects) -> Result<String, Error> {
+    let sorted: BTreeMap<_, _> = projects.projects.iter().collect();
+    // (fetch base, push base) -> remote name
+    let mut remotes: Vec<((String, Option<String>), String)> = vec![];
+    let mut body = String::new();
+    for (name, project) in sorted {
+        let (fetch, repo_name) = split_url(&project.fetch_url).ok_or_else(|| {
+            Error::General(format!(
+                "Project: {} fetch URL: '{}' can not be split into remote and name",
+                name, project.fetch_url
+            ))
+        })?;
+        let push = split_url(&project.push_url)
+            .filter(|(_, push_name)| *push_name == repo_name)
+            .map(|(base, _)| base.to_string());
+        let key = (fetch.to_string(), push);
+        let remote = match remotes.iter().find(|(k, _)| *k == key) {
+            Some((_, remote)) => remote.clone(),
+            None => {
+                let remote = projects
+                    .remotes
+                    .iter()
+                    .find(|(_, r)| r.fetch.trim_end_matches('/') == fetch)
+                    .map(|(name, _)| name.clone())
+                    .filter(|name| !remotes.iter().any(|(_, r)| r == name))
+                    .unwrap_or_else(|| format!("remote{}", remotes.len() + 1));
+                remotes.push((key, remote.clone()));
+                remote
+            }
+        };
+        let path = export::relative_path(project, &projects.projects_dir)?;
+        body += &format!(
+            "  <project name=\"{}\" path=\"{}\" remote=\"{}\" revision=\"{}\"",
+            escape(repo_name),
+            escape(&path.to_string_lossy()),
+            escape(&remote),
+            escape(&project.reference)
+        );
+        if !project.groups.is_empty() {
+            body += &format!(" groups=\"{}\"", escape(&project.groups.join(",")));
+        }
+        if let Some(depth) = project.depth {
+            body += &format!(" clone-depth=\"{}\"", depth);
+        }
+        let files: Vec<_> = project
+            .copy_files
+            .iter()
+            .map(|f| ("copyfile", f))
+            .chain(project.link_files.iter().map(|f| ("linkfile", f)))
+            .collect();
+        if files.is_empty() {
+            body += " />\n";
+            continue;
+        }
+        body += ">\n";
+        for (element, file) in files {
+            body += &format!(
+                "    <{} src=\"{}\" dest=\"{}\" />\n",
+                element,
+                escape(&file.src.to_string_lossy()),
+                escape(&file.dest.to_string_lossy())
+            );
+        }
+        body += "  </project>\n";
+    }
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<manifest>\n");
+    for ((fetch, push), name) in &remotes {
+        xml += &format!(
+            "  <remote name=\"{}\" fetch=\"{}\"",
+            escape(name),
+            escape(fetch)
+        );
+        if let Some(push) = push {
+            xml += &format!(" pushurl=\"{}\"", escape(push));
+        }
+        xml += " />\n";
+    }
+    xml += &body;
+    xml += "</manifest>\n";
+    Ok(xml)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    #[test]
+    fn test_to_mapping() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<manifest>
+  <remote name="aosp" fetch="https://android.googlesource.com" revision="refs/heads/main" />
+  <remote name="gl" fetch="https://gitlab.com/mike7b4" pushurl="ssh://git@gitlab.com/mike7b4" />
+  <default remote="aosp" revision="refs/tags/android-14.0.0_r1" sync-j="4" />
+  <include name="extra.xml" />
+  <notice>Ignored</notice>
+  <project name="platform/build" path="build/make" groups="pdk,tradefed" sync-c="true">
+    <copyfile src="core/root.mk" dest="Makefile" />
+    <linkfile src="CleanSpec.mk" dest="build/CleanSpec.mk" />
+  </project>
+  <project name="platform/build" path="build/soong" clone-depth="1" />
+  <project name="batchecker" remote="gl" revision="develop" groups="notdefault" />
+  <remove-project name="platform/art" />
+</manifest>"#;
+        let yaml = r"
+default_remote: aosp
+default_reference: android-14.0.0_r1
+remotes:
+    aosp:
+        fetch: https://android.googlesource.com
+    gl:
+        fetch: https://gitlab.com/mike7b4
+        push: ssh://git@gitlab.com/mike7b4
+include: [extra.xml]
+remove: [platform/art]
+projects:
+    platform/build:
+        path: build/make
+        reference: main
+        groups: [pdk, tradefed]
+        copy_files: [{src: core/root.mk, dest: Makefile}]
+        link_files: [{src: CleanSpec.mk, dest: build/CleanSpec.mk}]
+    build/soong:
+        repo_name: platform/build
+        path: build/soong
+        reference: main
+        depth: 1
+    batchecker:
+        reference: develop
+        remote: gl
+        groups: [notdefault]";
+        let expected: Value = serde_yaml::from_str(yaml).unwrap();
+        let mapping = to_mapping(Path::new("default.xml"), xml, None).unwrap();
+        assert_eq!(expected, Value::Mapping(mapping));
+        assert!(to_mapping(Path::new("a.xml"), "<foo/>", None).is_err());
+    }
+
+    #[test]
+    fn test_relative_fetch() {
+        let xml = r#"<manifest>
+  <remote name="aosp" fetch=".." />
+  <default remote="aosp" revision="main" />
+  <project name="platform/build" />
+</manifest>"#;
+        let fetch = |url| {
+            let mapping = to_mapping(Path::new("default.xml"), xml, url)?;
+            let remotes = &mapping[&Value::from("remotes")];
+            Ok::<_, Error>(remotes[&Value::from("aosp")][&Value::from("fetch")].clone())
+        };
+        assert_eq!(
+            Value::from("https://android.googlesource.com/"),
+            fetch(Some("https://android.googlesource.com/platform/manifest")).unwrap()
+        );
+        assert!(matches!(fetch(None), Err(Error::Manifest(_))));
+        assert!(matches!(
+            fetch(Some("https://host/manifest")),
+            Err(Error::Manifest(_))
+        ));
+
+        let resolve = |url, fetch| resolve_fetch(url, fetch);
+        assert_eq!(
+            Some(String::from("https://host/a/b/mirror")),
+            resolve("https://host/a/b/manifest.git/", "./mirror")
+        );
+        assert_eq!(
+            Some(String::from("git@host:a")),
+            resolve("git@host:a/b/manifest", "..")
+        );
+        assert_eq!(
+            Some(String::from("/srv/git")),
+            resolve("/srv/git/manifest", ".")
+        );
+        assert_eq!(
+            Some(String::from("file:///srv")),
+            resolve("file:///srv/git/manifest", "..")
+        );
+        assert_eq!(None, resolve("git@host:manifest", ".."));
+    }
+
+    #[test]
+    fn test_project_collision() {
+        let xml = r#"<manifest>
+  <remote name="aosp" fetch="https://android.googlesource.com" />
+  <default remote="aosp" revision="main" />
+  <project name="platform/build" path="build/make" />
+  <project name="platform/build" path="build/make" />
+</manifest>"#;
+        assert!(matches!(
+            to_mapping(Path::new("default.xml"), xml, None),
+            Err(Error::Manifest(_))
+        ));
+    }
+
+    #[test]
+    fn test_to_xml() {
+        let yaml: &str = r"---
+            projects_dir: /src
+            remotes:
+                gl:
+                    fetch: https://gitlab.com/mike7b4/
+            projects:
+                batchecker:
+                    fetch_url: https://gitlab.com/mike7b4/batchecker.git
+                    push_url: ssh://git@gitlab.com/mike7b4/batchecker.git
+                    path: /src/apps/batchecker
+                    reference: main
+                    groups: [apps, rust]
+                    copy_files: [{src: Makefile, dest: Makefile}]
+                linux:
+                    fetch_url: https://github.com/torvalds/linux
+                    path: /src/linux
+                    reference: v6.1
+                    depth: 1";
+        let manifest = serde_yaml::from_str::<GlProjects>(yaml).unwrap();
+        assert_eq!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<manifest>
+  <remote name="gl" fetch="https://gitlab.com/mike7b4" pushurl="ssh://git@gitlab.com/mike7b4" />
+  <remote name="remote2" fetch="https://github.com/torvalds" />
+  <project name="batchecker.git" path="apps/batchecker" remote="gl" revision="main" groups="apps,rust">
+    <copyfile src="Makefile" dest="Makefile" />
+  </project>
+  <project name="linux" path="linux" remote="remote2" revision="v6.1" clone-depth="1" />
+</manifest>
+"#,
+            to_xml(&manifest).unwrap()
+        );
+        let mut outside = manifest.clone();
+        outside.projects.get_mut("linux").unwrap().path = PathBuf::from("/opt/linux");
+        assert!(matches!(to_xml(&outside), Err(Error::General(_))));
+        let mapping =
+            to_mapping(Path::new("export.xml"), &to_xml(&manifest).unwrap(), None).unwrap();
+        let projects = mapping.get(&Value::from("projects")).unwrap();
+        assert_eq!(2, projects.as_mapping().unwrap().len());
+    }
+}
@@ -0,0 +1,446 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// What a sync is doing with a project.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Clone,
+    Fetch,
+    /// Resolving deltas of the received objects.
+    Resolve,
+    Checkout,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Phase::Clone => f.pad("clone"),
+            Phase::Fetch => f.pad("fetch"),
+            Phase::Resolve => f.pad("resolve"),
+            Phase::Checkout => f.pad("checkout"),
+        }
+    }
+}
+
+/// Progress of an operation on one project.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The operation has started.
+    Started { project: String },
+    /// A clone or fetch has started.
+    Phase { project: String, phase: Phase },
+    /// Objects received by a clone or fetch.
+    Transfer {
+        project: String,
+        received_objects: usize,
+        indexed_objects: usize,
+        total_objects: usize,
+        received_bytes: usize,
+    },
+    /// Deltas resolved after all objects has been received.
+    Deltas {
+        project: String,
+        indexed_deltas: usize,
+        total_deltas: usize,
+    },
+    /// The working tree is updated to 'commit'.
+    Checkout { project: String, commit: String },
+    /// The operation succeeded.
+    Finished { project: String },
+    /// The operation failed.
+    Failed { project: String, error: String },
+}
+
+impl Event {
+    pub fn project(&self) -> &str {
+        match self {
+            Event::Started { project }
+            | Event::Phase { project, .. }
+            | Event::Transfer { project, .. }
+            | Event::Deltas { project, .. }
+            | Event::Checkout { project, .. }
+            | Event::Finished { project }
+            | Event::Failed { project, .. } => project,
+        }
+    }
+}
+
+/// Receives the events of every project, it is called from the worker threads.
+pub trait Reporter: Send + Sync {
+    fn report(&self, event: &Event);
+    /// Called when the operation has ended for every project.
+    fn finish(&self) {}
+}
+
+/// Reporter shared by the worker threads and the git callbacks.
+pub type SharedReporter = Arc<dyn Reporter>;
+
+/// Throw away every event.
+pub struct Quiet;
+
+impl Reporter for Quiet {
+    fn report(&self, _event: &Event) {}
+}
+
+/// Write a line to stderr when a project starts, changes phase and ends.
+/// Used instead of Live when stderr is not a terminal.
+pub struct Plain;
+
+impl Reporter for Plain {
+    fn report(&self, event: &Event) {
+        let line = match event {
+            Event::Started { project } => format!("{}: started", project),
+            Event::Phase { project, phase } => format!("{}: {}", project, phase),
+            Event::Checkout { project, commit } => {
+                format!("{}: checkout {}", project, short(commit))
+            }
+            Event::Finished { project } => format!("{}: done", project),
+            Event::Failed { project, .. } => format!("{}: failed", project),
+            Event::Transfer { .. } | Event::Deltas { .. } => return,
+        };
+        eprintln!("{}", line);
+    }
+}
+
+/// Write every event as a JSON object on a line of its own to stderr.
+pub struct JsonLines;
+
+impl Reporter for JsonLines {
+    fn report(&self, event: &Event) {
+        if let Ok(line) = serde_json::to_string(event) {
+            let mut stderr = std::io::stderr().lock();
+            writeln!(stderr, "{}", line).ok();
+        }
+    }
+}
+
+/// Returns the first 7 characters of a commit id.
+fn short(commit: &str) -> &str {
+    &commit[..7.min(commit.len())]
+}
+
+/// Returns 'n' bytes in B, KiB, MiB or GiB.
+fn human_bytes(n: usize) -> String {
+    if n < 1024 {
+        return format!("{} B", n);
+    }
+    let mut value = n as f64 / 1024.0;
+    for unit in ["KiB", "MiB"] {
+        if value < 1024.0 {
+            return format!("{:.1} {}", value, unit);
+        }
+        value /= 1024.0;
+    }
+    format!("{:.1} GiB", value)
+}
+
+/// A project that is running.
+struct Active {
+    phase: Option<Phase>,
+    received_objects: usize,
+    total_objects: usize,
+    received_bytes: usize,
+    indexed_deltas: usize,
+    total_deltas: usize,
+    commit: String,
+    /// When the current phase started, used for the throughput.
+    since: Instant,
+}
+
+impl Active {
+    fn new() -> Self {
+        Self {
+            phase: None,
+            received_objects: 0,
+            total_objects: 0,
+            received_bytes: 0,
+            indexed_deltas: 0,
+            total_deltas: 0,
+            commit: String::new(),
+            since: Instant::now(),
+        }
+    }
+
+    fn set_phase(&mut self, phase: Phase) {
+        if self.phase != Some(phase) {
+            self.phase = Some(phase);
+            self.since = Instant::now();
+        }
+    }
+
+    /// Update with 'event', returns false if the project has ended.
+    fn update(&mut self, event: &Event) -> bool {
+        match event {
+            Event::Started { .. } => {}
+            Event::Phase { phase, .. } => self.set_phase(*phase),
+            Event::Transfer {
+                received_objects,
+                total_objects,
+                received_bytes,
+                ..
+            } => {
+                self.received_objects = *received_objects;
+                self.total_objects = *total_objects;
+                self.received_bytes = *received_bytes;
+            }
+            Event::Deltas {
+                indexed_deltas,
+                total_deltas,
+                ..
+            } => {
+                self.set_phase(Phase::Resolve);
+                self.indexed_deltas = *indexed_deltas;
+                self.total_deltas = *total_deltas;
+            }
+            Event::Checkout { commit, .. } => {
+                self.set_phase(Phase::Checkout);
+                self.commit = commit.clone();
+            }
+            Event::Finished { .. } | Event::Failed { .. } => return false,
+        }
+        true
+    }
+
+    /// Returns the phase and its progress, 'elapsed' is the time spent in the phase.
+    fn render(&self, elapsed: Duration) -> String {
+        match self.phase {
+            None => String::from("starting"),
+            Some(phase @ (Phase::Clone | Phase::Fetch)) if self.total_objects > 0 => {
+                let rate = self.received_bytes as f64 / elapsed.as_secs_f64().max(0.001);
+                format!(
+                    "{:<8}  {}/{} objects  {}  {}/s",
+                    phase,
+                    self.received_objects,
+                    self.total_objects,
+                    human_bytes(self.received_bytes),
+                    human_bytes(rate as usize)
+                )
+            }
+            Some(Phase::Resolve) => format!(
+                "{:<8}  {}/{} deltas",
+                Phase::Resolve,
+                self.indexed_deltas,
+                self.total_deltas
+            ),
+            Some(Phase::Checkout) => format!("{:<8}  {}", Phase::Checkout, short(&self.commit)),
+            Some(phase) => phase.to_string(),
+        }
+    }
+}
+
+/// What Live has drawn and the projects that has ended.
+struct Screen {
+    active: BTreeMap<String, Active>,
+    finished: usize,
+    failed: usize,
+    /// Number of lines drawn last time.
+    drawn: usize,
+    last_draw: Option<Instant>,
+    started: Instant,
+}
+
+impl Screen {
+    /// Move up to the first line drawn last time and clear everything below it.
+    fn clear(&mut self) -> String {
+        let mut out = String::new();
+        if self.drawn > 0 {
+            out += &format!("\x1b[{}A", self.drawn);
+        }
+        out += "\r\x1b[0J";
+        self.drawn = 0;
+        out
+    }
+
+    /// One line per running project followed by a line with the totals.
+    fn render(&self) -> Vec<String> {
+        let width = self
+            .active
+            .keys()
+            .map(|name| name.chars().count())
+            .max()
+            .unwrap_or_default();
+        let mut lines: Vec<String> = self
+            .active
+            .iter()
+            .map(|(name, active)| {
+                format!(
+                    "{:<width$}  {}",
+                    name,
+                    active.render(active.since.elapsed()),
+                    width = width
+                )
+            })
+            .collect();
+        lines.push(format!(
+            "{} running, {} done, {} failed",
+            self.active.len(),
+            self.finished,
+            self.failed
+        ));
+        lines
+    }
+}
+
+///
+/// Dashboard on stderr with one line per running project showing the phase,
+/// objects, bytes and throughput. Ended projects are collapsed into the totals
+/// and a summary is written when the operation has ended.
+pub struct Live {
+    screen: Mutex<Screen>,
+}
+
+/// Min time between two redraws caused by progress events.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+impl Live {
+    pub fn new() -> Self {
+        Self {
+            screen: Mutex::new(Screen {
+                active: BTreeMap::new(),
+                finished: 0,
+                failed: 0,
+                drawn: 0,
+                last_draw: None,
+                started: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl Default for Live {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn write_stderr(out: &str) {
+    let mut stderr = std::io::stderr().lock();
+    stderr.write_all(out.as_bytes()).ok();
+    stderr.flush().ok();
+}
+
+impl Reporter for Live {
+    fn report(&self, event: &Event) {
+        let mut screen = match self.screen.lock() {
+            Ok(screen) => screen,
+            Err(_) => return,
+        };
+        let project = event.project();
+        let running = screen
+            .active
+            .entry(project.to_string())
+            .or_insert_with(Active::new)
+            .update(event);
+        if !running {
+            screen.active.remove(project);
+            match event {
+                Event::Failed { .. } => screen.failed += 1,
+                _ => screen.finished += 1,
+            }
+        }
+        let progress = matches!(event, Event::Transfer { .. } | Event::Deltas { .. });
+        if progress
+            && screen
+                .last_draw
+                .is_some_and(|t| t.elapsed() < REDRAW_INTERVAL)
+        {
+            return;
+        }
+        let mut out = screen.clear();
+        let lines = screen.render();
+        for line in &lines {
+            out += line;
+            out += "\n";
+        }
+        screen.drawn = lines.len();
+        screen.last_draw = Some(Instant::now());
+        write_stderr(&out);
+    }
+
+    fn finish(&self) {
+        let mut screen = match self.screen.lock() {
+            Ok(screen) => screen,
+            Err(_) => return,
+        };
+        let mut out = screen.clear();
+        out += &format!(
+            "{} done, {} failed in {:.1}s\n",
+            screen.finished,
+            screen.failed,
+            screen.started.elapsed().as_secs_f64()
+        );
+        write_stderr(&out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_event_json() {
+        let event = Event::Deltas {
+            project: String::from("linux"),
+            indexed_deltas: 1,
+            total_deltas: 2,
+        };
+        assert_eq!(
+            r#"{"event":"deltas","project":"linux","indexed_deltas":1,"total_deltas":2}"#,
+            serde_json::to_string(&event).unwrap()
+        );
+        assert_eq!("linux", event.project());
+        assert_eq!(
+            r#"{"event":"phase","project":"linux","phase":"clone"}"#,
+            serde_json::to_string(&Event::Phase {
+                project: String::from("linux"),
+                phase: Phase::Clone
+            })
+            .unwrap()
+        );
+        assert_eq!(
+            r#"{"event":"failed","project":"a","error":"e"}"#,
+            serde_json::to_string(&Event::Failed {
+                project: String::from("a"),
+                error: String::from("e")
+            })
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_live_lines() {
+        assert_eq!("512 B", human_bytes(512));
+        assert_eq!("1.5 MiB", human_bytes(3 * 512 * 1024));
+        let mut active = Active::new();
+        assert_eq!("starting", active.render(Duration::from_secs(1)));
+        active.update(&Event::Phase {
+            project: String::from("a"),
+            phase: Phase::Fetch,
+        });
+        active.update(&Event::Transfer {
+            project: String::from("a"),
+            received_objects: 10,
+            indexed_objects: 5,
+            total_objects: 20,
+            received_bytes: 2048,
+        });
+        assert_eq!(
+            "fetch     10/20 objects  2.0 KiB  1.0 KiB/s",
+            active.render(Duration::from_secs(2))
+        );
+        active.update(&Event::Deltas {
+            project: String::from("a"),
+            indexed_deltas: 1,
+            total_deltas: 3,
+        });
+        assert_eq!("resolve   1/3 deltas", active.render(Duration::ZERO));
+        assert!(!active.update(&Event::Finished {
+            project: String::from("a")
+        }));
+    }
+}
@@ -22,23 +22,29 @@ impl<F: FnOnce()> FnBox for F {
 }
 
 type Job = Box<dyn FnBox + Send + 'static>;
+type Cancelled = Arc<dyn Fn() -> bool + Send + Sync>;
 
 impl ThreadPool {
     /// Create a new ThreadPool.
     ///
     /// The size is the number of threads in the pool.
+    /// Jobs still in the queue when `cancelled` returns true are dropped without being run.
     ///
     /// # Panics
     ///
     /// The `new` function will panic if the size is zero.
-    pub fn new(size: usize) -> ThreadPool {
+    pub fn new<C>(size: usize, cancelled: C) -> ThreadPool
+    where
+        C: Fn() -> bool + Send + Sync + 'static,
+    {
         assert!(size > 0);
 
+        let cancelled: Cancelled = Arc::new(cancelled);
         let mut workers = Vec::with_capacity(size);
         let (sender, receiver) = mpsc::channel();
         let receiver = Arc::new(Mutex::new(receiver));
         for id in 1..=size {
-            workers.push(Worker::new(id, receiver.clone()));
+            workers.push(Worker::new(id, receiver.clone(), cancelled.clone()));
         }
 
         ThreadPool { workers, sender }
@@ -76,10 +82,11 @@ struct Worker {
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Msg>>>) -> Worker {
+    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Msg>>>, cancelled: Cancelled) -> Worker {
         let thread = thread::spawn(move || loop {
             let msg = receiver.lock().unwrap().recv().unwrap();
             match msg {
+                Msg::Execute(job) if cancelled() => drop(job),
                 Msg::Execute(job) => job.call_box(),
                 Msg::Terminate => break,
             }
@@ -0,0 +1,938 @@
+use crate::cancel::CancelToken;
+use crate::config::GlConfig;
+use crate::error::{Error, Failure, Result};
+use crate::git::{ChangedFiles, Git, OnDirty, ProjectStatus, SyncContext, SyncReport};
+use crate::lock::{GlLock, LockedProject};
+use crate::manifest::{self, GlProject, GlProjects, Sort};
+use crate::process::{self, Output, Timeout};
+use crate::report::{Event, Quiet, Reporter, SharedReporter};
+use crate::threadpool::ThreadPool;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How an operation ended for one project.
+#[derive(Debug)]
+pub enum Outcome<T> {
+    Done(T),
+    Failed(Error),
+    /// Failed because the operation was interrupted while it was running.
+    Interrupted(Error),
+    /// Dropped from the queue after an interrupt or a failure with fail_fast.
+    NotStarted,
+    /// Not started because the project it depends on, directly or
+    /// through other projects, failed.
+    Blocked(String),
+}
+
+/// Outcome of an operation on the project 'name'.
+#[derive(Debug)]
+pub struct ProjectOutcome<T> {
+    pub name: String,
+    pub outcome: Outcome<T>,
+}
+
+/// Outcomes of an operation, in the same order as the projects it was run on.
+#[derive(Debug)]
+pub struct Outcomes<T> {
+    pub projects: Vec<ProjectOutcome<T>>,
+    /// True if the operation was interrupted.
+    pub interrupted: bool,
+}
+
+impl<T> Outcomes<T> {
+    /// Projects that failed, including the ones that failed because of an interrupt.
+    pub fn errors(&self) -> impl Iterator<Item = (&str, &Error)> {
+        self.projects.iter().filter_map(|p| match &p.outcome {
+            Outcome::Failed(e) | Outcome::Interrupted(e) => Some((p.name.as_str(), e)),
+            _ => None,
+        })
+    }
+
+    /// Projects that completed successfully.
+    pub fn done(&self) -> impl Iterator<Item = (&str, &T)> {
+        self.projects.iter().filter_map(|p| match &p.outcome {
+            Outcome::Done(value) => Some((p.name.as_str(), value)),
+            _ => None,
+        })
+    }
+
+    /// Names of the projects with an outcome matching 'f'.
+    pub fn names(&self, f: impl Fn(&Outcome<T>) -> bool) -> Vec<String> {
+        self.projects
+            .iter()
+            .filter(|p| f(&p.outcome))
+            .map(|p| p.name.clone())
+            .collect()
+    }
+
+    /// How the operation failed or None if every project succeeded.
+    pub fn failure(&self) -> Option<Failure> {
+        if !self.interrupted && self.errors().next().is_none() {
+            return None;
+        }
+        Some(Failure::classify(
+            self.errors().map(|(_, e)| e),
+            self.done().count(),
+            self.interrupted,
+        ))
+    }
+}
+
+/// Options for sync.
+#[derive(Debug, Clone, Copy)]
+pub struct SyncOptions {
+    pub on_dirty: OnDirty,
+    /// Checkout the commits in the lock file of the manifest.
+    pub locked: bool,
+    /// Override the depth of every project.
+    pub depth: Option<u32>,
+    /// Override the fetch retries of every project.
+    pub retries: Option<u32>,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        Self {
+            on_dirty: OnDirty::Skip,
+            locked: false,
+            depth: None,
+            retries: None,
+        }
+    }
+}
+
+/// Options for for_each.
+#[derive(Debug, Clone)]
+pub struct ForEachOptions {
+    /// Max run time, overrides the timeout of the project.
+    pub timeout: Option<Duration>,
+    /// Time between SIGTERM and SIGKILL when the command has timed out.
+    pub kill_grace: Duration,
+    pub output: Output,
+    /// Write the stdout of the commands to stderr, used when stdout holds a machine readable report.
+    pub stdout_to_stderr: bool,
+}
+
+impl Default for ForEachOptions {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            kill_grace: Duration::from_secs(5),
+            output: Output::Inherit,
+            stdout_to_stderr: false,
+        }
+    }
+}
+
+///
+/// Copy the copy_files and link the link_files of 'project' to 'projects_dir'.
+/// Existing files and links are replaced.
+///
+/// # Error
+/// Error::Manifest if a file is outside the project or the projects_dir.
+/// Error::General if a file could not be copied or linked.
+fn install_files(project: &GlProject, projects_dir: &Path) -> Result<()> {
+    let files = project
+        .copy_files
+        .iter()
+        .map(|f| (f, false))
+        .chain(project.link_files.iter().map(|f| (f, true)));
+    for (file, link) in files {
+        file.check()
+            .map_err(|e| Error::Manifest(format!("Project: {} {}", project.name, e)))?;
+        let src = project.path.join(&file.src);
+        let dest = projects_dir.join(&file.dest);
+        let res = dest
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|()| match std::fs::symlink_metadata(&dest) {
+                Ok(_) => std::fs::remove_file(&dest),
+                Err(_) => Ok(()),
+            })
+            .and_then(|()| {
+                if link {
+                    symlink(&src, &dest)
+                } else {
+                    std::fs::copy(&src, &dest).map(|_| ())
+                }
+            });
+        res.map_err(|e| {
+            Error::General(format!(
+                "{}: {} '{}' to '{}' cause: {}",
+                project.name,
+                if link { "link" } else { "copy" },
+                src.display(),
+                dest.display(),
+                e
+            ))
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(src: &Path, dest: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(src, dest)
+}
+
+#[cfg(windows)]
+fn symlink(src: &Path, dest: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(src, dest)
+}
+
+///
+/// A manifest, with its includes and local manifests, and the
+/// configuration directory it was loaded from.
+/// Operations are run on 'jobs' projects in parallel and return
+/// an outcome per project instead of printing it, progress is sent to the reporter.
+pub struct Workspace {
+    manifest_file: PathBuf,
+    config_home: PathBuf,
+    projects: GlProjects,
+    /// Number of projects an operation is run on in parallel.
+    pub jobs: usize,
+    /// Do not start any more projects after a project has failed.
+    pub fail_fast: bool,
+    /// Order of the selected projects.
+    pub sort: Sort,
+    config: GlConfig,
+    reporter: SharedReporter,
+    cancel: CancelToken,
+}
+
+impl Workspace {
+    ///
+    /// Read config.yaml from 'config_home' and the manifest with its includes
+    /// and the local manifests in 'config_home'/local_manifests.
+    ///
+    /// # Error
+    ///
+    /// Error::General if the configuration can not be read.
+    /// Error::Manifest.
+    pub fn open<P: AsRef<Path>, C: AsRef<Path>>(manifest_file: P, config_home: C) -> Result<Self> {
+        let manifest_file = manifest_file.as_ref().to_path_buf();
+        let config_home = config_home.as_ref().to_path_buf();
+        let config = GlConfig::try_from_yaml(&config_home.join("config.yaml"))?;
+        log::info!("Read manifest from: '{}'", manifest_file.display());
+        let projects =
+            GlProjects::try_from_yaml(&manifest_file, Some(config_home.join("local_manifests")))?;
+        Ok(Self {
+            manifest_file,
+            config_home,
+            projects,
+            jobs: 1,
+            fail_fast: false,
+            sort: Sort::default(),
+            config,
+            reporter: Arc::new(Quiet),
+            cancel: CancelToken::new(),
+        })
+    }
+
+    pub fn manifest(&self) -> &GlProjects {
+        &self.projects
+    }
+
+    pub fn manifest_file(&self) -> &Path {
+        &self.manifest_file
+    }
+
+    pub fn config_home(&self) -> &Path {
+        &self.config_home
+    }
+
+    /// The configuration read from config.yaml in the config home.
+    pub fn config(&self) -> &GlConfig {
+        &self.config
+    }
+
+    /// Stop running and queued projects when 'cancel' is cancelled, default is a new token.
+    pub fn set_cancel_token(&mut self, cancel: CancelToken) {
+        self.cancel = cancel;
+    }
+
+    pub fn cancel_token(&self) -> &CancelToken {
+        &self.cancel
+    }
+
+    /// Send the progress of every project to 'reporter', default is Quiet.
+    pub fn set_reporter<R: Reporter + 'static>(&mut self, reporter: R) {
+        self.reporter = Arc::new(reporter);
+    }
+
+    ///
+    /// Projects selected by 'names' regardless of groups,
+    /// or by 'groups' if no names are given, in the order of 'sort'.
+    pub fn select(&self, names: &[String], groups: &[String]) -> Vec<GlProject> {
+        let mut selected: Vec<GlProject> = if names.is_empty() {
+            self.projects.select(groups).into_values().collect()
+        } else {
+            self.projects
+                .projects
+                .values()
+                .filter(|project| names.contains(&project.name))
+                .cloned()
+                .collect()
+        };
+        self.sort.sort(&mut selected);
+        selected
+    }
+
+    ///
+    /// Sync 'projects', a project is synced after the projects it depends on.
+    ///
+    /// # Error
+    /// Error::Manifest if 'locked' is set and a project is not in the lock file.
+    pub fn sync(
+        &self,
+        mut projects: Vec<GlProject>,
+        options: &SyncOptions,
+    ) -> Result<Outcomes<SyncReport>> {
+        if options.locked {
+            self.apply_lock(&mut projects)?;
+        }
+        for project in projects.iter_mut() {
+            if options.depth.is_some() {
+                project.depth = options.depth;
+            }
+            if options.retries.is_some() {
+                project.fetch_retries = options.retries;
+            }
+        }
+        let on_dirty = options.on_dirty;
+        let projects_dir = self.projects.projects_dir.clone();
+        Ok(self.run(projects, true, move |_, project, ctx| {
+            log::info!("Sync: {}", project.name);
+            let report = Git::sync(&project.name, project, on_dirty, ctx)?;
+            install_files(project, &projects_dir)?;
+            Ok(report)
+        }))
+    }
+
+    /// Fetch the full history of shallow 'projects'.
+    pub fn unshallow(&self, projects: Vec<GlProject>) -> Outcomes<()> {
+        self.run(projects, false, |_, project, ctx| {
+            log::info!("Unshallow: {}", project.name);
+            Git::unshallow(&project.name, project, ctx)
+        })
+    }
+
+    /// Status of the working tree of 'projects'.
+    pub fn status(&self, projects: Vec<GlProject>) -> Outcomes<ProjectStatus> {
+        self.run(projects, false, |_, project, _| {
+            Git::open(&project.path)
+                .and_then(|mut git| git.project_status(&project.name, &project.reference))
+        })
+    }
+
+    /// Run the shell 'command' in every project after the projects it depends on.
+    pub fn for_each(
+        &self,
+        projects: Vec<GlProject>,
+        command: &str,
+        options: &ForEachOptions,
+    ) -> Outcomes<()> {
+        let command = command.to_string();
+        let options = options.clone();
+        let manifest_file = self.manifest_file.clone();
+        let count = projects.len();
+        self.run(projects, true, move |index, project, ctx| {
+            let timeout = Timeout {
+                limit: options.timeout.or(project.timeout),
+                grace: options.kill_grace,
+            };
+            let env = process::project_env(project, &manifest_file, index + 1, count);
+            process::spawn_shell_and_wait(
+                &project.name,
+                &project.path,
+                command.clone(),
+                timeout,
+                &options.output,
+                options.stdout_to_stderr,
+                &env,
+                &ctx.cancel,
+            )
+        })
+    }
+
+    /// Changed files of 'projects', empty for projects without changes.
+    pub fn changed(&self, projects: Vec<GlProject>) -> Outcomes<ChangedFiles> {
+        let projects = projects
+            .into_iter()
+            .map(|project| {
+                let outcome = match Git::open(&project.path).and_then(|repo| repo.changed()) {
+                    Ok(files) => Outcome::Done(files),
+                    Err(e) => Outcome::Failed(e),
+                };
+                ProjectOutcome {
+                    name: project.name,
+                    outcome,
+                }
+            })
+            .collect();
+        Outcomes {
+            projects,
+            interrupted: false,
+        }
+    }
+
+    ///
+    /// Write the commit checked out in every project to the lock file
+    /// of the manifest and return its path.
+    /// Nothing is written if a project could not be resolved.
+    ///
+    /// # Error
+    /// Error::Summary if a project could not be resolved.
+    pub fn lock(&self, projects: &[GlProject]) -> Result<PathBuf> {
+        let mut lock = GlLock::default();
+        let mut errors = vec![];
+        for project in projects {
+            match Git::open(&project.path).and_then(|git| git.head_commit()) {
+                Ok(commit) => {
+                    lock.projects.insert(
+                        project.name.clone(),
+                        LockedProject {
+                            commit: commit.to_string(),
+                            reference: project.reference.clone(),
+                            fetch_url: project.fetch_url.clone(),
+                        },
+                    );
+                }
+                Err(e) => {
+                    log::error!(
+                        "Project: {}: {} Make sure sync has been run",
+                        project.name,
+                        e
+                    );
+                    errors.push(project.name.clone());
+                }
+            }
+        }
+        if !errors.is_empty() {
+            let failure = if lock.projects.is_empty() {
+                Failure::Total
+            } else {
+                Failure::Partial
+            };
+            return Err(Error::Summary(
+                failure,
+                format!(
+                    "Lock file not written, could not resolve:\n\n{}\n",
+                    errors.join("\n")
+                ),
+            ));
+        }
+        let lock_file = GlLock::path_for(&self.manifest_file);
+        lock.save_to_yaml(&lock_file)?;
+        Ok(lock_file)
+    }
+
+    ///
+    /// Init a repository for 'project', add it to the manifest file
+    /// and run 'command' in it.
+    /// Only the manifest file itself is changed, not its includes or local manifests,
+    /// and the rest of it is kept as it is.
+    ///
+    /// # Error
+    /// Error::General if the project already exists.
+    pub fn create(&mut self, project: GlProject, command: &str, timeout: Timeout) -> Result<()> {
+        if self.projects.projects.contains_key(&project.name) {
+            return Err(Error::General(format!(
+                "Project: '{}' already exists",
+                project.name
+            )));
+        }
+        let repo = Git::init(&project.path)?;
+        repo.remote("origin", &project.fetch_url)?;
+        let env = process::project_env(&project, &self.manifest_file, 1, 1);
+        manifest::add_project(&self.manifest_file, &self.projects.manifest_entry(&project))?;
+        let path = project.path.clone();
+        let name = project.name.clone();
+        self.projects.insert(&name, project);
+        process::spawn_shell_and_wait(
+            &name,
+            &path,
+            command.into(),
+            timeout,
+            &Output::Inherit,
+            false,
+            &env,
+            &self.cancel,
+        )
+    }
+
+    ///
+    /// Replace the reference of every project with the commit in the lock file.
+    ///
+    /// # Error
+    /// Error::Manifest if the lock file can not be read or a project is not locked.
+    fn apply_lock(&self, projects: &mut [GlProject]) -> Result<()> {
+        let lock = GlLock::try_from_yaml(&GlLock::path_for(&self.manifest_file))?;
+        for project in projects.iter_mut() {
+            match lock.projects.get(&project.name) {
+                Some(locked) => project.reference = locked.commit.clone(),
+                None => {
+                    return Err(Error::Manifest(format!(
+                        "Project: {} is not in the lock file, run glrepo lock",
+                        project.name
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Run 'job' for every project on the thread pool and wait for all of them.
+    /// If 'ordered' a project is started when the projects it depends on has succeeded,
+    /// dependencies that are not in 'projects' are ignored.
+    /// Queued projects are dropped when the cancel token is cancelled, or when a project fails with fail_fast.
+    /// The reporter gets Started before and Finished or Failed after every job,
+    /// and finish when all jobs has ended.
+    fn run<T, F>(&self, projects: Vec<GlProject>, ordered: bool, job: F) -> Outcomes<T>
+    where
+        T: Send + 'static,
+        F: Fn(usize, &GlProject, &SyncContext) -> Result<T> + Send + Sync + 'static,
+    {
+        let ctx = SyncContext {
+            reporter: self.reporter.clone(),
+            credentials: self.config.credentials.clone(),
+            cancel: self.cancel.clone(),
+        };
+        let job = Arc::new(job);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let mut results: Vec<Option<Outcome<T>>> = projects.iter().map(|_| None).collect();
+        // Number of dependencies not done yet and the projects depending on each project.
+        let mut waiting = vec![0; projects.len()];
+        let mut dependents: Vec<Vec<usize>> = projects.iter().map(|_| vec![]).collect();
+        if ordered {
+            let indexes: HashMap<&str, usize> = projects
+                .iter()
+                .enumerate()
+                .map(|(index, project)| (project.name.as_str(), index))
+                .collect();
+            for (index, project) in projects.iter().enumerate() {
+                for dependency in &project.depends_on {
+                    if let Some(&dependency) = indexes.get(dependency.as_str()) {
+                        waiting[index] += 1;
+                        dependents[dependency].push(index);
+                    }
+                }
+            }
+        }
+        {
+            let pool = {
+                let stopped = stopped.clone();
+                let cancel = self.cancel.clone();
+                ThreadPool::new(self.jobs.max(1), move || {
+                    stopped.load(Ordering::SeqCst) || cancel.is_cancelled()
+                })
+            };
+            let (tx, rx) = channel();
+            let execute = |index: usize| {
+                // Created before the job is queued so a job dropped by the pool
+                // after an interrupt is reported as not started.
+                let sender = ResultSender {
+                    index,
+                    tx: tx.clone(),
+                    result: None,
+                };
+                let job = job.clone();
+                let stopped = stopped.clone();
+                let fail_fast = self.fail_fast;
+                let project = projects[index].clone();
+                let ctx = ctx.clone();
+                pool.execute(move || {
+                    let mut sender = sender;
+                    let reporter = &ctx.reporter;
+                    reporter.report(&Event::Started {
+                        project: project.name.clone(),
+                    });
+                    let result = job(index, &project, &ctx);
+                    match &result {
+                        Ok(_) => reporter.report(&Event::Finished {
+                            project: project.name.clone(),
+                        }),
+                        Err(e) => {
+                            reporter.report(&Event::Failed {
+                                project: project.name.clone(),
+                                error: e.to_string(),
+                            });
+                            if fail_fast {
+                                stopped.store(true, Ordering::SeqCst);
+                            }
+                        }
+                    }
+                    sender.result = Some(result);
+                });
+            };
+            let is_stopped = || stopped.load(Ordering::SeqCst) || self.cancel.is_cancelled();
+            let mut running = 0;
+            for (index, _) in waiting.iter().enumerate().filter(|(_, n)| **n == 0) {
+                if is_stopped() {
+                    break;
+                }
+                execute(index);
+                running += 1;
+            }
+            while running > 0 {
+                let Ok((index, result)) = rx.recv() else {
+                    break;
+                };
+                running -= 1;
+                let succeeded = matches!(result, Some(Ok(_)));
+                results[index] = result.map(|result| match result {
+                    Ok(value) => Outcome::Done(value),
+                    Err(e) => {
+                        log::error!("Project: {}: {}", projects[index].name, e);
+                        match e {
+                            Error::Interrupted(_) => Outcome::Interrupted(e),
+                            e => Outcome::Failed(e),
+                        }
+                    }
+                });
+                if matches!(results[index], Some(Outcome::Failed(_))) {
+                    block_dependents(&projects[index].name, index, &dependents, &mut results);
+                }
+                if !succeeded || is_stopped() {
+                    continue;
+                }
+                for &dependent in &dependents[index] {
+                    waiting[dependent] -= 1;
+                    if waiting[dependent] == 0 {
+                        execute(dependent);
+                        running += 1;
+                    }
+                }
+            }
+        }
+        self.reporter.finish();
+        Outcomes {
+            projects: projects
+                .into_iter()
+                .zip(results)
+                .map(|(project, outcome)| ProjectOutcome {
+                    name: project.name,
+                    outcome: outcome.unwrap_or(Outcome::NotStarted),
+                })
+                .collect(),
+            interrupted: self.cancel.is_cancelled(),
+        }
+    }
+}
+
+/// Sends the result of a job when dropped, None if the thread pool dropped the job without running it.
+struct ResultSender<T> {
+    index: usize,
+    tx: Sender<(usize, Option<Result<T>>)>,
+    result: Option<Result<T>>,
+}
+
+impl<T> Drop for ResultSender<T> {
+    fn drop(&mut self) {
+        self.tx.send((self.index, self.result.take())).ok();
+    }
+}
+
+/// Set the outcome of every project depending on 'index', directly or not, to Blocked by 'name'.
+fn block_dependents<T>(
+    name: &str,
+    index: usize,
+    dependents: &[Vec<usize>],
+    results: &mut [Option<Outcome<T>>],
+) {
+    for &dependent in &dependents[index] {
+        if results[dependent].is_none() {
+            results[dependent] = Some(Outcome::Blocked(name.to_string()));
+            block_dependents(name, dependent, dependents, results);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::FileMapping;
+    #[test]
+    fn test_outcomes_failure() {
+        let outcome = |name: &str, outcome| ProjectOutcome {
+            name: String::from(name),
+            outcome,
+        };
+        let mut outcomes = Outcomes {
+            projects: vec![
+                outcome("a", Outcome::Done(())),
+                outcome("b", Outcome::NotStarted),
+            ],
+            interrupted: false,
+        };
+        assert_eq!(None, outcomes.failure());
+        outcomes.projects.push(outcome(
+            "c",
+            Outcome::Failed(Error::General(String::from("failed"))),
+        ));
+        assert_eq!(Some(Failure::Partial), outcomes.failure());
+        assert_eq!(
+            vec!["b"],
+            outcomes.names(|o| matches!(o, Outcome::NotStarted))
+        );
+        outcomes.interrupted = true;
+        assert_eq!(Some(Failure::Interrupted), outcomes.failure());
+    }
+
+    #[test]
+    fn test_install_files() {
+        let dir = std::env::temp_dir().join("glrepo-test-install-files");
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut project = GlProject {
+            name: String::from("build"),
+            path: dir.join("build"),
+            ..Default::default()
+        };
+        std::fs::create_dir_all(project.path.join("core")).unwrap();
+        std::fs::write(project.path.join("core/root.mk"), "root").unwrap();
+        let file = |src: &str, dest: &str| FileMapping {
+            src: PathBuf::from(src),
+            dest: PathBuf::from(dest),
+        };
+        project.copy_files = vec![file("core/root.mk", "Makefile")];
+        project.link_files = vec![file("core/root.mk", "build/root.mk")];
+        install_files(&project, &dir).unwrap();
+        assert_eq!(
+            "root",
+            std::fs::read_to_string(dir.join("Makefile")).unwrap()
+        );
+        assert_eq!(
+            "root",
+            std::fs::read_to_string(dir.join("build/root.mk")).unwrap()
+        );
+
+        let outside = dir.join("outside");
+        std::fs::write(&outside, "keep").unwrap();
+        for (copy, link) in [
+            (file("core/root.mk", "../outside"), None),
+            (file("core/root.mk", &outside.to_string_lossy()), None),
+            (file("../outside", "copy"), None),
+            (
+                file("core/root.mk", "Makefile"),
+                Some(file("core/root.mk", "x/../../outside")),
+            ),
+        ] {
+            project.copy_files = vec![copy];
+            project.link_files = link.into_iter().collect();
+            assert!(matches!(
+                install_files(&project, &dir.join("build")),
+                Err(Error::Manifest(_))
+            ));
+        }
+        assert_eq!("keep", std::fs::read_to_string(&outside).unwrap());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// A workspace without a manifest running 'jobs' projects at a time.
+    fn test_workspace(jobs: usize) -> Workspace {
+        Workspace {
+            manifest_file: PathBuf::new(),
+            config_home: PathBuf::new(),
+            projects: GlProjects::default(),
+            jobs,
+            fail_fast: false,
+            sort: Sort::Manifest,
+            config: GlConfig::default(),
+            reporter: Arc::new(Quiet),
+            cancel: CancelToken::new(),
+        }
+    }
+
+    #[test]
+    fn test_run_cancelled() {
+        let project = |name: &str| GlProject {
+            name: String::from(name),
+            ..Default::default()
+        };
+        let workspace = test_workspace(2);
+        let projects = vec![project("stopped"), project("broken"), project("queued")];
+        // Both running projects have started before the interrupt.
+        let started = Arc::new(std::sync::Barrier::new(2));
+        let outcomes: Outcomes<()> = workspace.run(projects, false, move |_, project, ctx| {
+            started.wait();
+            match project.name.as_str() {
+                "stopped" => {
+                    ctx.cancel.cancel();
+                    Err(Error::Interrupted(project.name.clone()))
+                }
+                _ => {
+                    // Fails by itself after the interrupt.
+                    while !ctx.cancel.is_cancelled() {
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                    Err(Error::General(String::from("failed")))
+                }
+            }
+        });
+        assert_eq!(
+            vec!["stopped"],
+            outcomes.names(|o| matches!(o, Outcome::Interrupted(_)))
+        );
+        assert_eq!(
+            vec!["broken"],
+            outcomes.names(|o| matches!(o, Outcome::Failed(_)))
+        );
+        assert_eq!(
+            vec!["queued"],
+            outcomes.names(|o| matches!(o, Outcome::NotStarted))
+        );
+        assert_eq!(Some(Failure::Interrupted), outcomes.failure());
+    }
+
+    #[test]
+    fn test_for_each_timeout() {
+        let dir = std::env::temp_dir().join(format!("glrepo-for-each-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let project = GlProject {
+            name: String::from("p"),
+            path: dir.clone(),
+            timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let workspace = test_workspace(1);
+        let run = |timeout| {
+            let options = ForEachOptions {
+                timeout,
+                kill_grace: Duration::from_millis(100),
+                output: Output::Buffer,
+                ..Default::default()
+            };
+            workspace
+                .for_each(vec![project.clone()], "sleep 1", &options)
+                .failure()
+        };
+        // The project timeout applies without --timeout and --timeout overrides it.
+        assert_eq!(Some(Failure::Timeout), run(None));
+        assert_eq!(None, run(Some(Duration::from_secs(10))));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    /// Commit 'content' to main in the bare repository 'repo'.
+    fn commit(repo: &git2::Repository, content: &str) -> git2::Oid {
+        let blob = repo.blob(content.as_bytes()).unwrap();
+        let mut tree = repo.treebuilder(None).unwrap();
+        tree.insert("file", blob, 0o100644).unwrap();
+        let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+        let sig = git2::Signature::now("glrepo", "glrepo@example.com").unwrap();
+        let parent = repo
+            .find_reference("refs/heads/main")
+            .and_then(|r| r.peel_to_commit())
+            .ok();
+        let parents: Vec<_> = parent.iter().collect();
+        repo.commit(
+            Some("refs/heads/main"),
+            &sig,
+            &sig,
+            content,
+            &tree,
+            &parents,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_lock_and_sync_locked() {
+        let dir = std::env::temp_dir().join("glrepo-test-lock");
+        let _ = std::fs::remove_dir_all(&dir);
+        let up = git2::Repository::init_bare(dir.join("up.git")).unwrap();
+        up.set_head("refs/heads/main").unwrap();
+        let one = commit(&up, "one");
+        let project = GlProject {
+            name: String::from("p"),
+            path: dir.join("src/p"),
+            fetch_url: dir.join("up.git").to_string_lossy().to_string(),
+            reference: String::from("main"),
+            fetch_retries: Some(0),
+            ..Default::default()
+        };
+        let workspace = Workspace {
+            manifest_file: dir.join("default.yaml"),
+            ..test_workspace(1)
+        };
+        let sync = |locked| {
+            let options = SyncOptions {
+                locked,
+                ..Default::default()
+            };
+            let outcomes = workspace.sync(vec![project.clone()], &options).unwrap();
+            assert!(matches!(outcomes.projects[0].outcome, Outcome::Done(_)));
+            Git::open(&project.path).unwrap().head_commit().unwrap()
+        };
+        assert_eq!(one, sync(false));
+
+        // origin/main moves on without being checked out, HEAD is locked.
+        let two = commit(&up, "two");
+        let repo = git2::Repository::open(&project.path).unwrap();
+        repo.find_remote("origin")
+            .unwrap()
+            .fetch(&["main"], None, None)
+            .unwrap();
+        let lock_file = workspace.lock(std::slice::from_ref(&project)).unwrap();
+        let lock = GlLock::try_from_yaml(&lock_file).unwrap();
+        assert_eq!(one.to_string(), lock.projects["p"].commit);
+
+        commit(&up, "three");
+        assert_eq!(one, sync(true));
+
+        // An abbreviated commit id is resolved as well.
+        let mut lock = lock;
+        lock.projects.get_mut("p").unwrap().commit = two.to_string()[..10].to_string();
+        lock.save_to_yaml(&lock_file).unwrap();
+        assert_eq!(two, sync(true));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_run_dependencies() {
+        let project = |name: &str, depends_on: &[&str]| GlProject {
+            name: String::from(name),
+            depends_on: depends_on.iter().map(|d| d.to_string()).collect(),
+            ..Default::default()
+        };
+        let workspace = test_workspace(4);
+        let projects = vec![
+            project("app", &["lib", "unselected"]),
+            project("lib", &[]),
+            project("tool", &["app", "broken"]),
+            project("broken", &[]),
+            project("plugin", &["tool"]),
+        ];
+        let log = Arc::new(std::sync::Mutex::new(vec![]));
+        let events = log.clone();
+        let outcomes = workspace.run(projects, true, move |_, project, _| {
+            events
+                .lock()
+                .unwrap()
+                .push(format!("start {}", project.name));
+            std::thread::sleep(Duration::from_millis(20));
+            events.lock().unwrap().push(format!("end {}", project.name));
+            match project.name.as_str() {
+                "broken" => Err(Error::General(String::from("failed"))),
+                _ => Ok(()),
+            }
+        });
+        let log = log.lock().unwrap();
+        let position = |event: &str| log.iter().position(|e| e == event).unwrap();
+        assert!(position("end lib") < position("start app"));
+        assert!(!log.contains(&String::from("start tool")));
+        assert_eq!(
+            vec!["app", "lib"],
+            outcomes.names(|o| matches!(o, Outcome::Done(_)))
+        );
+        assert_eq!(
+            vec!["tool", "plugin"],
+            outcomes.names(|o| matches!(o, Outcome::Blocked(d) if d == "broken"))
+        );
+        assert_eq!(Some(Failure::Partial), outcomes.failure());
+    }
+}